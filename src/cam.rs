@@ -1,4 +1,5 @@
 use cgmath::{Vector2, Point2, EuclideanSpace};
+use ggez::graphics::Rect;
 
 pub struct Cam {
     pub(crate) center: Point2<f32>,
@@ -20,4 +21,34 @@ impl Cam {
     pub fn world_to_screen(&self, world_pos: Point2<f32>) -> Point2<f32> {
         (world_pos - self.center.to_vec()) * self.zoom + (self.last_vp_size * 0.5)
     }
+
+    pub fn screen_to_world(&self, screen_pos: Point2<f32>) -> Point2<f32> {
+        (screen_pos - self.last_vp_size * 0.5) / self.zoom + self.center.to_vec()
+    }
+
+    /// The part of the world that is on screen
+    pub fn visible_rect(&self) -> Rect {
+        let size = self.last_vp_size / self.zoom;
+        let top_left = self.center - size * 0.5;
+        Rect::new(top_left.x, top_left.y, size.x, size.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cam::Cam;
+    use crate::utils::mostly_eq;
+    use cgmath::Point2;
+
+    #[test]
+    fn test_screen_to_world_inverts_world_to_screen() {
+        let mut cam = Cam::new((800.0, 600.0).into());
+        cam.center = Point2::new(3.0, -2.0);
+        cam.zoom = 25.0;
+
+        let world = Point2::new(10.5, 4.25);
+        let back = cam.screen_to_world(cam.world_to_screen(world));
+        assert!(mostly_eq(back.x, world.x, 0.001) && mostly_eq(back.y, world.y, 0.001));
+        assert_eq!(cam.screen_to_world(Point2::new(400.0, 300.0)), cam.center);
+    }
 }
\ No newline at end of file
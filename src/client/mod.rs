@@ -0,0 +1,144 @@
+use async_std::prelude::*;
+use async_std::{net, task};
+use std::sync::mpsc;
+use crate::networking::{self, packets::{self, NetError, Packet, PlayerInput}};
+
+/// Connection to a server.
+/// The packets of the server are read by an async task and handed to the game by `poll`,
+/// the packets to the server are written by a thread of their own, so the game never waits for the network.
+pub struct Client {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    incoming: mpsc::Receiver<Result<Packet, NetError>>,
+    /// Id of our player, known once the server answered the `Join`
+    player_id: Option<u64>,
+    /// Tick of the next input
+    tick: u64,
+}
+
+impl Client {
+    /// Connects to the server and joins the game under the name
+    pub fn connect(address: &str, name: &str) -> Result<Client, NetError> {
+        let stream = task::block_on(net::TcpStream::connect(address)).map_err(NetError::Io)?;
+
+        let (outgoing, outgoing_rx) = mpsc::channel();
+        let writer = stream.clone();
+        std::thread::spawn(move || networking::write_packets(writer, outgoing_rx));
+
+        let (incoming_tx, incoming) = mpsc::channel();
+        task::spawn(read_packets(stream, incoming_tx));
+
+        let client = Client {
+            outgoing,
+            incoming,
+            player_id: None,
+            tick: 0,
+        };
+        client.send(&Packet::Join { player_id: 0, name: name.to_owned() });
+        Ok(client)
+    }
+
+    /// Sends the input for the next tick
+    pub fn send_input(&mut self, input: PlayerInput) {
+        let input = PlayerInput { tick: self.tick, ..input };
+        self.tick += 1;
+        // the server only lets us move our own player anyway
+        self.send(&Packet::PlayerInput { player_id: self.player_id.unwrap_or(0), input });
+    }
+
+    /// Packets received since the last call.
+    /// When the connection is lost the error is logged once and nothing arrives anymore.
+    pub fn poll(&mut self) -> Vec<Packet> {
+        let mut packets = vec![];
+        for res in self.incoming.try_iter() {
+            match res {
+                Ok(packet) => {
+                    // the first packet of the server is the answer to our join
+                    if let (Packet::Join { player_id, .. }, None) = (&packet, self.player_id) {
+                        println!("Joined as player {}", player_id);
+                        self.player_id = Some(*player_id);
+                    }
+                    packets.push(packet);
+                }
+                Err(e) => eprintln!("Lost connection to the server: {}", e),
+            }
+        }
+        packets
+    }
+
+    /// Packets after the connection is lost are dropped
+    fn send(&self, packet: &Packet) {
+        let _ = self.outgoing.send(packets::encode_packet(packet));
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // dropping the sender afterwards lets the writer close the connection
+        if let Some(player_id) = self.player_id {
+            self.send(&Packet::Leave { player_id });
+        }
+    }
+}
+
+/// Reads the packets of the server until the first error, which is passed on as well
+async fn read_packets(mut stream: net::TcpStream, received: mpsc::Sender<Result<Packet, NetError>>) {
+    loop {
+        let mut header_buf = [0u8; packets::HEADER_SIZE];
+        let res = match stream.read_exact(&mut header_buf).await {
+            Ok(()) => packets::decode_next_packet(&header_buf, &mut stream).await,
+            Err(e) => Err(NetError::Io(e))
+        };
+
+        let failed = res.is_err();
+        if received.send(res).is_err() || failed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::Client;
+    use crate::networking::packets::{decode_next_packet, encode_packet, EntityNetworkData, Packet, PlayerInput, HEADER_SIZE};
+    use async_std::prelude::*;
+    use async_std::{net, task};
+    use std::time::{Duration, Instant};
+
+    async fn read_packet(stream: &mut net::TcpStream) -> Packet {
+        let mut header = [0u8; HEADER_SIZE];
+        stream.read_exact(&mut header).await.unwrap();
+        decode_next_packet(&header, stream).await.unwrap()
+    }
+
+    #[test]
+    fn test_client_joins_and_receives_snapshots() {
+        let listener = task::block_on(net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // a server that welcomes one client and sends one snapshot
+        let server = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(read_packet(&mut stream).await, Packet::Join { player_id: 0, name: "tester".to_owned() });
+
+            stream.write_all(&encode_packet(&Packet::Join { player_id: 5, name: "tester".to_owned() })).await.unwrap();
+            let snapshot = [EntityNetworkData { id: 5, pos: (1.0, 2.0), vel: (0.0, 0.0) }];
+            stream.write_all(&encode_packet(&Packet::EntitiesFrameData(Box::new(snapshot)))).await.unwrap();
+
+            read_packet(&mut stream).await
+        });
+
+        let mut client = Client::connect(&address, "tester").unwrap();
+        let mut packets = vec![];
+        let start = Instant::now();
+        while packets.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            packets.extend(client.poll());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(packets.as_slice(), [Packet::Join { player_id: 5, .. }, Packet::EntitiesFrameData(_)]));
+
+        // the inputs are numbered by the client and carry our id
+        client.send_input(PlayerInput { tick: 99, move_x: 1.0, jump: true, drop_through: false });
+        let input = PlayerInput { tick: 0, move_x: 1.0, jump: true, drop_through: false };
+        assert_eq!(task::block_on(server), Packet::PlayerInput { player_id: 5, input });
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{
+    level::{LevelError, LevelFormat},
+    tiles::{TileId, TileRegistry},
+    world::{TileLayer, Tilemap},
+};
+
+use cgmath::Point2;
+use ggez::graphics::{self, Color, DrawMode, DrawParam, Image, Mesh, Rect, Text};
+use ggez::{Context, GameResult};
+
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+
+/// Flood fills bigger than this are refused, the map is infinite
+const MAX_FLOOD_FILL_CELLS: usize = 4096;
+/// Size of a palette entry on screen in pixels
+const PALETTE_TILE_SIZE: f32 = 40.0;
+const PALETTE_MARGIN: f32 = 8.0;
+/// Top of the palette, below the status text
+const PALETTE_TOP: f32 = 32.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tool {
+    /// Sets the cells the mouse is dragged over
+    Brush,
+    /// Fills the rectangle between the cells where the mouse was pressed and released
+    BoxFill,
+    /// Replaces the connected area of equal cells under the mouse
+    FloodFill,
+}
+
+/// A cell changed by the editor
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CellChange {
+    layer: TileLayer,
+    x: isize,
+    y: isize,
+    before: TileId,
+    after: TileId,
+}
+
+/// One undo step, e.g. a brush stroke or a fill
+type Edit = Vec<CellChange>;
+
+/// Level editing on top of the running game: painting cells with the mouse, fills, undo/redo and saving.
+/// `Game` forwards input to it while it's active.
+pub struct Editor {
+    pub active: bool,
+    pub tool: Tool,
+    /// Tile painted with the left mouse button, the right one erases
+    pub selected: TileId,
+    /// All tools paint on this layer
+    pub layer: TileLayer,
+    /// Cell under the mouse
+    hovered: Option<(isize, isize)>,
+    /// Changes of the brush stroke in progress, the last cell it painted and its tile
+    stroke: Option<(Edit, (isize, isize), TileId)>,
+    /// Where a box fill drag started and the tile it fills with
+    box_start: Option<((isize, isize), TileId)>,
+    undo_stack: Vec<Edit>,
+    redo_stack: Vec<Edit>,
+    /// Binary level file, or text for `.txt` files
+    save_path: PathBuf,
+}
+
+impl Editor {
+    pub fn new(registry: &TileRegistry, save_path: PathBuf) -> Editor {
+        Editor {
+            active: false,
+            tool: Tool::Brush,
+            selected: registry.tiles().next().map_or(TileId::EMPTY, |t| t.id),
+            layer: TileLayer::Main,
+            hovered: None,
+            stroke: None,
+            box_start: None,
+            undo_stack: vec![],
+            redo_stack: vec![],
+            save_path,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+        // a stroke cut short by closing the editor is still undoable
+        if let Some((edit, _, _)) = self.stroke.take() {
+            self.push_edit(edit);
+        }
+        self.box_start = None;
+    }
+
+    /// Cell containing the world position
+    pub fn cell_at(world_pos: Point2<f32>) -> (isize, isize) {
+        (world_pos.x.floor() as isize, world_pos.y.floor() as isize)
+    }
+
+    /// Starts applying the active tool with `tile` at `cell`
+    pub fn press(&mut self, tiles: &mut Tilemap, cell: (isize, isize), tile: TileId) {
+        match self.tool {
+            Tool::Brush => {
+                let mut edit = vec![];
+                set_cell(tiles, self.layer, cell, tile, &mut edit);
+                self.stroke = Some((edit, cell, tile));
+            }
+            Tool::BoxFill => self.box_start = Some((cell, tile)),
+            Tool::FloodFill => match flood_fill(tiles, self.layer, cell, tile) {
+                Ok(edit) => self.push_edit(edit),
+                Err(e) => println!("Flood fill failed: {}", e),
+            },
+        }
+    }
+
+    /// Mouse moved to `cell`, continues a brush stroke
+    pub fn drag(&mut self, tiles: &mut Tilemap, cell: (isize, isize)) {
+        self.hovered = Some(cell);
+
+        if let Some((edit, last, tile)) = &mut self.stroke {
+            // fast mouse movements skip cells, so paint the line between them
+            for cell in line(*last, cell) {
+                set_cell(tiles, self.layer, cell, *tile, edit);
+            }
+            *last = cell;
+        }
+    }
+
+    /// Finishes the stroke or box fill
+    pub fn release(&mut self, tiles: &mut Tilemap, cell: (isize, isize)) {
+        if let Some((edit, _, _)) = self.stroke.take() {
+            self.push_edit(edit);
+        }
+
+        if let Some((start, tile)) = self.box_start.take() {
+            let mut edit = vec![];
+            for y in start.1.min(cell.1)..=start.1.max(cell.1) {
+                for x in start.0.min(cell.0)..=start.0.max(cell.0) {
+                    set_cell(tiles, self.layer, (x, y), tile, &mut edit);
+                }
+            }
+            self.push_edit(edit);
+        }
+    }
+
+    fn push_edit(&mut self, edit: Edit) {
+        if !edit.is_empty() {
+            self.undo_stack.push(edit);
+            self.redo_stack.clear();
+        }
+    }
+
+    pub fn undo(&mut self, tiles: &mut Tilemap) {
+        if let Some(edit) = self.undo_stack.pop() {
+            for change in edit.iter().rev() {
+                tiles.set_layer_cell(change.layer, change.x, change.y, change.before);
+            }
+            self.redo_stack.push(edit);
+        }
+    }
+
+    pub fn redo(&mut self, tiles: &mut Tilemap) {
+        if let Some(edit) = self.redo_stack.pop() {
+            for change in edit.iter() {
+                tiles.set_layer_cell(change.layer, change.x, change.y, change.after);
+            }
+            self.undo_stack.push(edit);
+        }
+    }
+
+    pub fn save(&self, tiles: &Tilemap) -> Result<(), LevelError> {
+        let format = match self.save_path.extension().and_then(|ext| ext.to_str()) {
+            Some("txt") => LevelFormat::Text,
+            _ => LevelFormat::Binary,
+        };
+        tiles.save(&self.save_path, format)?;
+        println!("Level saved to {}", self.save_path.display());
+        Ok(())
+    }
+
+    /// Selects the next (or with `backwards` the previous) tile of the registry
+    pub fn cycle_selected(&mut self, registry: &TileRegistry, backwards: bool) {
+        let ids: Vec<TileId> = registry.tiles().map(|t| t.id).collect();
+        if ids.is_empty() {
+            return;
+        }
+
+        let current = ids.iter().position(|&id| id == self.selected).unwrap_or(0);
+        let next = if backwards {
+            (current + ids.len() - 1) % ids.len()
+        } else {
+            (current + 1) % ids.len()
+        };
+        self.selected = ids[next];
+    }
+
+    /// Switches to the next layer, from back to front
+    pub fn cycle_layer(&mut self) {
+        self.layer = TileLayer::ALL[(self.layer.index() + 1) % TileLayer::ALL.len()];
+    }
+
+    /// The palette entry at the screen position
+    pub fn palette_tile_at(&self, registry: &TileRegistry, screen_pos: Point2<f32>) -> Option<TileId> {
+        registry
+            .tiles()
+            .enumerate()
+            .find(|(idx, _)| Editor::palette_rect(*idx).contains(screen_pos))
+            .map(|(_, tile)| tile.id)
+    }
+
+    fn palette_rect(idx: usize) -> Rect {
+        Rect::new(
+            PALETTE_MARGIN + idx as f32 * (PALETTE_TILE_SIZE + PALETTE_MARGIN),
+            PALETTE_TOP,
+            PALETTE_TILE_SIZE,
+            PALETTE_TILE_SIZE,
+        )
+    }
+
+    /// Outlines the hovered cell or the box being filled
+    pub fn draw_worldspace(&self, ctx: &mut Context) -> GameResult<()> {
+        let outline = match (self.box_start, self.hovered) {
+            (Some(((sx, sy), _)), Some((hx, hy))) => Rect::new(
+                sx.min(hx) as f32,
+                sy.min(hy) as f32,
+                ((sx - hx).abs() + 1) as f32,
+                ((sy - hy).abs() + 1) as f32,
+            ),
+            (None, Some((hx, hy))) => Rect::new(hx as f32, hy as f32, 1.0, 1.0),
+            _ => return Ok(()),
+        };
+
+        let mesh = Mesh::new_rectangle(ctx, DrawMode::stroke(0.05), outline, Color::from_rgb(255, 255, 0))?;
+        graphics::draw(ctx, &mesh, DrawParam::default())
+    }
+
+    /// Status line and tile palette
+    pub fn draw_screenspace(&self, ctx: &mut Context, registry: &TileRegistry, tile_tex: &Image) -> GameResult<()> {
+        let status = Text::new(format!(
+            "EDITOR  tool: {:?} (B/R/F)  tile: {}  layer: {} (L)  undo: Ctrl+Z  redo: Ctrl+Y  save: Ctrl+S",
+            self.tool,
+            registry.get(self.selected).name,
+            self.layer.name()
+        ));
+        graphics::draw(ctx, &status, DrawParam::default().dest(Point2::new(PALETTE_MARGIN, PALETTE_MARGIN)))?;
+
+        let uv_size = 1.0 / registry.atlas_size() as f32;
+        for (idx, tile) in registry.tiles().enumerate() {
+            let rect = Editor::palette_rect(idx);
+            let slot = tile.atlas_index;
+            let src = Rect::new(
+                (slot % registry.atlas_size()) as f32 * uv_size,
+                (slot / registry.atlas_size()) as f32 * uv_size,
+                uv_size,
+                uv_size,
+            );
+
+            graphics::draw(
+                ctx,
+                tile_tex,
+                DrawParam::default()
+                    .src(src)
+                    .dest(Point2::new(rect.x, rect.y))
+                    .scale(cgmath::Vector2::new(
+                        rect.w / (tile_tex.width() as f32 * uv_size),
+                        rect.h / (tile_tex.height() as f32 * uv_size),
+                    )),
+            )?;
+
+            if tile.id == self.selected {
+                let frame = Mesh::new_rectangle(ctx, DrawMode::stroke(3.0), rect, Color::from_rgb(255, 255, 0))?;
+                graphics::draw(ctx, &frame, DrawParam::default())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets the cell and records the change, if there is one
+fn set_cell(tiles: &mut Tilemap, layer: TileLayer, (x, y): (isize, isize), tile: TileId, edit: &mut Edit) {
+    let before = tiles.get_layer_cell(layer, x, y);
+    if before != tile {
+        tiles.set_layer_cell(layer, x, y, tile);
+        edit.push(CellChange { layer, x, y, before, after: tile });
+    }
+}
+
+/// Cells from `from` (exclusive) to `to` (inclusive), 8-connected
+fn line(from: (isize, isize), to: (isize, isize)) -> Vec<(isize, isize)> {
+    let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs());
+    (1..=steps)
+        .map(|step| {
+            let t = step as f32 / steps as f32;
+            (
+                from.0 + ((to.0 - from.0) as f32 * t).round() as isize,
+                from.1 + ((to.1 - from.1) as f32 * t).round() as isize,
+            )
+        })
+        .collect()
+}
+
+fn flood_fill(tiles: &mut Tilemap, layer: TileLayer, start: (isize, isize), tile: TileId) -> Result<Edit, &'static str> {
+    let target = tiles.get_layer_cell(layer, start.0, start.1);
+    if target == tile {
+        return Ok(vec![]);
+    }
+
+    // collect the area first, so a fill that is too big doesn't change anything
+    let mut area = HashSet::new();
+    let mut queue = VecDeque::new();
+    area.insert(start);
+    queue.push_back(start);
+
+    while let Some((x, y)) = queue.pop_front() {
+        for &neighbour in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].iter() {
+            if tiles.get_layer_cell(layer, neighbour.0, neighbour.1) == target && area.insert(neighbour) {
+                if area.len() > MAX_FLOOD_FILL_CELLS {
+                    return Err("area is too big, is it enclosed?");
+                }
+                queue.push_back(neighbour);
+            }
+        }
+    }
+
+    let mut edit = vec![];
+    for cell in area {
+        set_cell(tiles, layer, cell, tile, &mut edit);
+    }
+    Ok(edit)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::editor::{Editor, Tool};
+    use crate::tiles::{test_registry, TileId};
+    use crate::world::Tilemap;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_editor_fills_and_undo() {
+        let mut tiles = Tilemap::new(test_registry());
+        let mut editor = Editor::new(tiles.registry(), PathBuf::from("level.lvl"));
+        let stone = tiles.registry().id_by_name("stone").unwrap();
+        let platform = tiles.registry().id_by_name("platform").unwrap();
+
+        // brush stroke skipping cells
+        editor.press(&mut tiles, (0, 0), stone);
+        editor.drag(&mut tiles, (4, 0));
+        editor.release(&mut tiles, (4, 0));
+        assert!((0..=4).all(|x| tiles.get_cell(x, 0) == stone));
+
+        // walls around a 3x2 room, then flood its inside
+        editor.tool = Tool::BoxFill;
+        editor.press(&mut tiles, (0, 3), stone);
+        editor.release(&mut tiles, (4, 3));
+        for &x in [0, 4].iter() {
+            editor.press(&mut tiles, (x, 1), stone);
+            editor.release(&mut tiles, (x, 2));
+        }
+        editor.tool = Tool::FloodFill;
+        editor.press(&mut tiles, (2, 2), platform);
+        assert_eq!(tiles.get_cell(1, 1), platform);
+        assert_eq!(tiles.get_cell(3, 2), platform);
+        assert_eq!(tiles.get_cell(5, 2), TileId::EMPTY);
+
+        // the open outside is refused
+        editor.press(&mut tiles, (10, 10), platform);
+        assert_eq!(tiles.get_cell(10, 10), TileId::EMPTY);
+
+        editor.undo(&mut tiles);
+        assert_eq!(tiles.get_cell(2, 2), TileId::EMPTY);
+        editor.redo(&mut tiles);
+        assert_eq!(tiles.get_cell(2, 2), platform);
+
+        for _ in 0..5 {
+            editor.undo(&mut tiles);
+        }
+        assert_eq!(tiles.non_empty_chunks().unwrap().len(), 0);
+
+        // closing the editor in the middle of a stroke
+        editor.tool = Tool::Brush;
+        editor.press(&mut tiles, (0, 0), stone);
+        editor.drag(&mut tiles, (2, 0));
+        editor.toggle();
+        editor.undo(&mut tiles);
+        assert_eq!(tiles.non_empty_chunks().unwrap().len(), 0);
+    }
+}
@@ -1,13 +1,16 @@
 use crate::{
-    utils::{
-        Shared, SharedWeak, shared
-    },
-    world::{CellType, Tilemap},
-    player::Player,
-    physics::RigidBody,
+    utils::{Shared, SharedWeak},
+    simulation::Simulation,
+    timestep::{self, FixedTimestep},
     cam::Cam,
-    DebugDrawable,
-    physics
+    client::Client,
+    editor::{Editor, Tool},
+    level,
+    networking::packets::{Packet, PlayerInput},
+    player::Player,
+    tiles::TileId,
+    world::TileLayer,
+    DebugDrawable
 };
 
 use ggez::{
@@ -21,64 +24,147 @@ use ggez::{
     }
 };
 
-use cgmath::{Point2, Vector2, prelude::*};
+use cgmath::{Vector2, prelude::*};
 
+use std::collections::HashMap;
 use std::sync::atomic::Ordering;
-use std::cell::RefCell;
 use std::rc::Rc;
+use std::path::PathBuf;
 
+/// The ggez shell around the `Simulation`: renders it and feeds it with input.
 pub struct Game {
-    tiles: Shared<Tilemap>,
+    pub sim: Simulation,
+    timestep: FixedTimestep,
+    tile_tex: Image,
     pub cam: Cam,
-    players: Vec<Shared<Player>>,
-    rigidbodies: Vec<SharedWeak<RigidBody>>,
+    /// Pauses the simulation while active
+    editor: Editor,
     debug_drawables: Vec<SharedWeak<dyn DebugDrawable>>,
-    frame_debug_drawables: Vec<Box<dyn DebugDrawable>>
+    frame_debug_drawables: Vec<Box<dyn DebugDrawable>>,
+    /// Input of the local player, jumping and dropping through are collected until the next tick
+    input: PlayerInput,
+    /// Set when playing on a server, which then simulates everything and the game only shows it
+    client: Option<Client>,
+    /// Players of the server by their id
+    remote_players: HashMap<u64, Shared<Player>>
 }
 
 impl Game {
-    pub fn new(ctx: &mut Context) -> Game {
+    /// The editor saves the level to `level_path`.
+    /// With a client the simulation should start out empty, the server sends the chunks and players.
+    pub fn new(ctx: &mut Context, sim: Simulation, level_path: PathBuf, client: Option<Client>) -> Game {
         let tile_tex = Image::new(ctx, "/tiles.png").expect("No texture for tiles found");
-        let mut rbs = vec![];
+        let editor = Editor::new(sim.tiles.borrow().registry(), level_path);
         let mut game = Game {
-            tiles: shared(Tilemap::new(tile_tex, &mut rbs)),
+            sim,
+            timestep: FixedTimestep::new(timestep::DEFAULT_TICK_RATE, timestep::DEFAULT_MAX_CATCH_UP_STEPS),
+            tile_tex,
             cam: Cam::new(ggez::graphics::drawable_size(ctx).into()),
-            players: vec![],
-            rigidbodies: rbs,
+            editor,
             debug_drawables: vec![],
-            frame_debug_drawables: vec![]
+            frame_debug_drawables: vec![],
+            input: PlayerInput::default(),
+            client,
+            remote_players: HashMap::new()
         };
 
-        // generate boxes
-        for y in 8..=10 {
-            for x in 12..20 {
-                let tile_rb = game.tiles.borrow_mut().set_cell(x, y, CellType::Stone);
-                if let Some(rb) = tile_rb {
-                    game.rigidbodies.push(rb);
-                }
+        game.debug_drawables.push(Rc::downgrade(&game.sim.tiles) as _);
+        if game.client.is_none() {
+            for idx in 0..2 {
+                let pos = game.sim.player_spawn(idx);
+                game.init_player(pos);
             }
         }
 
-        game.debug_drawables.push(Rc::downgrade(&game.tiles) as _);
-        game.init_player(ctx, Point2::new(15.0, 1.0));
-        game.init_player(ctx, Point2::new(17.0, 1.0));
-
         game
     }
 
-    fn init_player(&mut self, ctx: &mut Context, pos: cgmath::Point2<f32>) -> GameResult<()> {
-        let player = Rc::new(RefCell::new(Player::create(ctx, pos, 0)?));
+    fn init_player(&mut self, pos: cgmath::Point2<f32>) -> Shared<Player> {
+        let player = self.sim.spawn_player(pos);
+        self.debug_drawables.push(Rc::downgrade(&player) as _);
+        player
+    }
 
-        self.rigidbodies.push(Rc::downgrade(&player.borrow().rb));
+    /// Applies what the server sent since the last frame
+    fn receive_from_server(&mut self) {
+        let packets = match &mut self.client {
+            Some(client) => client.poll(),
+            None => return,
+        };
 
-        self.debug_drawables.push(Rc::downgrade(&player) as _);
-        self.players.push(player);
+        for packet in packets {
+            match packet {
+                Packet::EntitiesFrameData(entities) => {
+                    for entity in entities.iter() {
+                        let pos = cgmath::Point2::new(entity.pos.0, entity.pos.1);
+                        let player = self.remote_player(entity.id, pos);
+                        let rb = player.borrow().rb.clone();
+                        rb.borrow_mut().set_state(pos, Vector2::new(entity.vel.0, entity.vel.1));
+                    }
+                }
+                Packet::ChunkData { x, y, chunk } => {
+                    let coords = (x as isize, y as isize);
+                    let mut tiles = self.sim.tiles.borrow_mut();
+                    match level::decode_chunk(&chunk, coords, tiles.registry()) {
+                        Ok(layers) => tiles.set_chunk(coords, &layers),
+                        Err(e) => println!("Received a broken chunk {:?}: {}", coords, e),
+                    }
+                }
+                Packet::Join { player_id, name } => println!("{} joined as player {}", name, player_id),
+                Packet::Leave { player_id } => {
+                    println!("Player {} left", player_id);
+                    if let Some(player) = self.remote_players.remove(&player_id) {
+                        self.sim.remove_player(&player);
+                    }
+                }
+                Packet::Chat { player_id, message } => println!("Player {}: {}", player_id, message),
+                Packet::PlayerInput { .. } => {}
+            }
+        }
+    }
 
-        Ok(())
+    /// The player of the server with the id, spawned at `pos` the first time it shows up
+    fn remote_player(&mut self, id: u64, pos: cgmath::Point2<f32>) -> Shared<Player> {
+        if let Some(player) = self.remote_players.get(&id) {
+            return player.clone();
+        }
+
+        let player = self.init_player(pos);
+        self.remote_players.insert(id, player.clone());
+        player
+    }
+
+    /// Interpolation factor between the last two simulation ticks for rendering
+    pub fn interpolation_alpha(&self) -> f32 {
+        self.timestep.alpha()
     }
 
     pub fn screen_to_world(&self, screen_pos: cgmath::Point2<f32>) -> cgmath::Point2<f32> {
-        unimplemented!()
+        self.cam.screen_to_world(screen_pos)
+    }
+
+    /// Editor shortcuts
+    fn editor_key(&mut self, key: KeyCode, mods: KeyMods) {
+        let mut tiles = self.sim.tiles.borrow_mut();
+        let ctrl = mods.contains(KeyMods::CTRL);
+
+        match key {
+            KeyCode::B => self.editor.tool = Tool::Brush,
+            KeyCode::R => self.editor.tool = Tool::BoxFill,
+            KeyCode::F => self.editor.tool = Tool::FloodFill,
+            KeyCode::L => self.editor.cycle_layer(),
+            KeyCode::Q => self.editor.cycle_selected(tiles.registry(), true),
+            KeyCode::E => self.editor.cycle_selected(tiles.registry(), false),
+            KeyCode::Z if ctrl && mods.contains(KeyMods::SHIFT) => self.editor.redo(&mut tiles),
+            KeyCode::Z if ctrl => self.editor.undo(&mut tiles),
+            KeyCode::Y if ctrl => self.editor.redo(&mut tiles),
+            KeyCode::S if ctrl => {
+                if let Err(e) = self.editor.save(&tiles) {
+                    println!("Failed to save level: {}", e);
+                }
+            }
+            _ => {}
+        }
     }
 }
 
@@ -97,18 +183,34 @@ impl EventHandler for Game {
         if ggez::timer::ticks(ctx) % 100 == 0 {
             println!("fps: {}", ggez::timer::fps(ctx));
 
-            println!("chunks in storage: {}", self.tiles.borrow().chunks_stored());
+            let tiles = self.sim.tiles.borrow();
+            println!("chunks loaded: {}, unloaded: {}", tiles.chunks_stored(), tiles.chunks_unloaded());
         }
 
-        let player_move: cgmath::Vector2<f32> = if ggez::input::keyboard::is_key_pressed(ctx, KeyCode::A) {
-            (-1.0, 0.0).into()
+        self.input.move_x = if ggez::input::keyboard::is_key_pressed(ctx, KeyCode::A) {
+            -1.0
         } else if ggez::input::keyboard::is_key_pressed(ctx, KeyCode::D) {
-            (1.0, 0.0).into()
-        } else { (0., 0.).into() };
+            1.0
+        } else { 0.0 };
+
+        let ticks = if self.editor.active { 0 } else { self.timestep.advance(delta) };
+        for _ in 0..ticks {
+            let tick_delta = self.timestep.tick_delta();
+            match &mut self.client {
+                Some(client) => client.send_input(self.input),
+                None => {
+                    self.sim.players[0].borrow_mut().apply_input(&self.input, tick_delta);
+                    self.sim.step(tick_delta, &mut self.frame_debug_drawables);
+                }
+            }
+            self.input.jump = false;
+            self.input.drop_through = false;
+        }
 
-        *self.players[0].borrow_mut().rb.borrow_mut().velocity_mut() += player_move * delta * 10.;
+        self.receive_from_server();
 
-        physics::step_rb_sim(&mut self.rigidbodies, delta, &mut self.frame_debug_drawables);
+        // also while the editor is open, its camera moves as well
+        self.sim.stream_chunks(&[self.cam.visible_rect()]);
 
         Ok(())
     }
@@ -120,6 +222,7 @@ impl EventHandler for Game {
         let scale = self.cam.zoom;
 
         let viewport_size: cgmath::Vector2<f32> = ggez::graphics::drawable_size(ctx).into();
+        self.cam.last_vp_size = viewport_size;
         let scaled_viewport_size =
             cgmath::Vector2::new(viewport_size.x / scale, viewport_size.y / scale);
 
@@ -134,7 +237,10 @@ impl EventHandler for Game {
         //graphics::set_transform(ctx, param.to_matrix());
         graphics::apply_transformations(ctx);
 
-        self.tiles.borrow_mut().draw(ctx)?;
+        self.sim
+            .tiles
+            .borrow_mut()
+            .draw(ctx, &self.tile_tex, &self.cam, &[TileLayer::Background, TileLayer::Main])?;
 
         // draw debug drawables
         for weak_drawable in &self.debug_drawables {
@@ -150,6 +256,13 @@ impl EventHandler for Game {
             frame_drawable.debug_draw_worldspace(ctx, &self)?;
         }
 
+        // in front of the players
+        self.sim.tiles.borrow_mut().draw(ctx, &self.tile_tex, &self.cam, &[TileLayer::Foreground])?;
+
+        if self.editor.active {
+            self.editor.draw_worldspace(ctx)?;
+        }
+
         graphics::origin(ctx);
 
         // draw screeen space debug
@@ -163,14 +276,35 @@ impl EventHandler for Game {
             frame_drawable.debug_draw_screenspace(ctx, &self)?;
         }
 
+        if self.editor.active {
+            let tiles = self.sim.tiles.borrow();
+            self.editor.draw_screenspace(ctx, tiles.registry(), &self.tile_tex)?;
+        }
+
 
         graphics::present(ctx)
     }
 
     fn key_down_event(&mut self, ctx: &mut Context, key: KeyCode, mods: KeyMods, _: bool) {
         //self.ui.update_search(key, self);
+        if key == KeyCode::Tab {
+            // the server neither sees nor keeps edits of our copy of its world
+            if self.client.is_some() {
+                println!("The editor is only available in local mode");
+                return;
+            }
+            self.editor.toggle();
+            return;
+        }
+        if self.editor.active {
+            // the keys of the player would only apply once the editor is closed
+            self.editor_key(key, mods);
+            return;
+        }
+
         match key {
-            KeyCode::W => self.players[0].borrow_mut().jump(12.0),
+            KeyCode::W => self.input.jump = true,
+            KeyCode::S => self.input.drop_through = true,
             _ => {}
         }
     }
@@ -179,16 +313,50 @@ impl EventHandler for Game {
         let dx = x - self.cam.last_mouse_pos.x;
         let dy = y - self.cam.last_mouse_pos.y;
 
-        if ggez::input::mouse::button_pressed(ctx, MouseButton::Left) {
+        // the editor uses the left button for painting
+        let pan_button = if self.editor.active { MouseButton::Middle } else { MouseButton::Left };
+        if ggez::input::mouse::button_pressed(ctx, pan_button) {
             self.cam.center -= cgmath::Vector2::new(dx, dy) / self.cam.zoom;
         }
 
+        if self.editor.active {
+            let cell = Editor::cell_at(self.screen_to_world(cgmath::Point2::new(x, y)));
+            self.editor.drag(&mut self.sim.tiles.borrow_mut(), cell);
+        }
+
         //println!("{:?}", self.cam.center);
 
         self.cam.last_mouse_pos.x = x;
         self.cam.last_mouse_pos.y = y;
     }
 
+    fn mouse_button_down_event(&mut self, _ctx: &mut Context, button: MouseButton, x: f32, y: f32) {
+        if !self.editor.active {
+            return;
+        }
+
+        let mut tiles = self.sim.tiles.borrow_mut();
+        let screen_pos = cgmath::Point2::new(x, y);
+        if let Some(tile) = self.editor.palette_tile_at(tiles.registry(), screen_pos) {
+            self.editor.selected = tile;
+            return;
+        }
+
+        let cell = Editor::cell_at(self.cam.screen_to_world(screen_pos));
+        match button {
+            MouseButton::Left => self.editor.press(&mut tiles, cell, self.editor.selected),
+            MouseButton::Right => self.editor.press(&mut tiles, cell, TileId::EMPTY),
+            _ => {}
+        }
+    }
+
+    fn mouse_button_up_event(&mut self, _ctx: &mut Context, button: MouseButton, x: f32, y: f32) {
+        if self.editor.active && (button == MouseButton::Left || button == MouseButton::Right) {
+            let cell = Editor::cell_at(self.screen_to_world(cgmath::Point2::new(x, y)));
+            self.editor.release(&mut self.sim.tiles.borrow_mut(), cell);
+        }
+    }
+
     fn mouse_wheel_event(&mut self, _ctx: &mut Context, _x: f32, y: f32) {
         self.cam.zoom = (self.cam.zoom + y).max(4.0);
     }
@@ -0,0 +1,539 @@
+use crate::tiles::{TileId, TileRegistry};
+use crate::world::{ChunkLayers, LevelChunks, TileLayer, Tilemap, CELLS_PER_CHUNK, CHUNK_SIZE, LAYER_COUNT};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Start of binary level files
+const MAGIC: &[u8; 4] = b"PLVL";
+/// Start of the files of single chunks that were streamed out
+const CHUNK_MAGIC: &[u8; 4] = b"PCHK";
+/// First word of text level files
+const TEXT_MAGIC: &str = "platformer-level";
+/// Bumped on every incompatible change of either format
+pub const FORMAT_VERSION: u16 = 2;
+/// Version 1 levels only have the main layer
+const OLDEST_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelFormat {
+    /// Compact, the cells of each chunk are run length encoded
+    Binary,
+    /// One line per row of cells, for diffs and editing by hand
+    Text,
+}
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io(std::io::Error),
+    /// The file starts with neither the binary nor the text header
+    NotALevel,
+    UnsupportedVersion(u16),
+    /// The level was saved with another tile registry, so its tile ids mean something else
+    RegistryMismatch { expected: u64, found: u64 },
+    /// The file is damaged, with a description of what and where
+    Corrupt(String),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelError::Io(e) => write!(f, "level file could not be accessed: {}", e),
+            LevelError::NotALevel => write!(f, "not a level file"),
+            LevelError::UnsupportedVersion(v) => {
+                write!(f, "level format version {} is not supported (expected {})", v, FORMAT_VERSION)
+            }
+            LevelError::RegistryMismatch { expected, found } => write!(
+                f,
+                "level was saved with tile registry {:016x}, but the loaded one is {:016x}",
+                found, expected
+            ),
+            LevelError::Corrupt(msg) => write!(f, "level file is corrupt: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LevelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LevelError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Tilemap {
+    pub fn save(&self, path: &Path, format: LevelFormat) -> Result<(), LevelError> {
+        std::fs::write(path, encode(self, format)?).map_err(LevelError::Io)
+    }
+
+    /// Loads a level saved in either format
+    pub fn load(path: &Path, registry: Arc<TileRegistry>) -> Result<Tilemap, LevelError> {
+        let bytes = std::fs::read(path).map_err(LevelError::Io)?;
+        decode(&bytes, registry)
+    }
+}
+
+/// Encodes every chunk of the level, including the ones that are streamed out to disk
+pub fn encode(tiles: &Tilemap, format: LevelFormat) -> Result<Vec<u8>, LevelError> {
+    let chunks = tiles.non_empty_chunks()?;
+    Ok(match format {
+        LevelFormat::Binary => encode_binary(tiles.registry(), &chunks),
+        LevelFormat::Text => encode_text(tiles.registry(), &chunks).into_bytes(),
+    })
+}
+
+/// Decodes a level, the format is detected from the header
+pub fn decode(bytes: &[u8], registry: Arc<TileRegistry>) -> Result<Tilemap, LevelError> {
+    let chunks = if bytes.starts_with(MAGIC) {
+        decode_binary(&bytes[MAGIC.len()..], &registry)?
+    } else if bytes.starts_with(TEXT_MAGIC.as_bytes()) {
+        let text = std::str::from_utf8(bytes).map_err(|e| LevelError::Corrupt(format!("invalid utf-8: {}", e)))?;
+        decode_text(text, &registry)?
+    } else {
+        return Err(LevelError::NotALevel);
+    };
+
+    let mut tiles = Tilemap::new(registry);
+    for (coords, layers) in chunks {
+        tiles.set_chunk(coords, &layers);
+    }
+    Ok(tiles)
+}
+
+/// Splits the cells into (count, tile) runs
+fn run_lengths(cells: &[TileId]) -> Vec<(u16, TileId)> {
+    let mut runs: Vec<(u16, TileId)> = vec![];
+    for &cell in cells {
+        match runs.last_mut() {
+            Some((count, tile)) if *tile == cell => *count += 1,
+            _ => runs.push((1, cell)),
+        }
+    }
+    runs
+}
+
+/// A mask of the stored layers, then the runs of each of them
+fn write_layers(out: &mut Vec<u8>, layers: &ChunkLayers) {
+    let mask = TileLayer::ALL
+        .iter()
+        .filter(|layer| !layers[layer.index()].is_empty())
+        .fold(0u8, |mask, layer| mask | 1 << layer.index());
+    out.push(mask);
+
+    for cells in layers.iter().filter(|cells| !cells.is_empty()) {
+        let runs = run_lengths(cells);
+        out.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+        for (count, tile) in runs {
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&tile.0.to_le_bytes());
+        }
+    }
+}
+
+// Binary layout, all numbers little endian:
+//   magic "PLVL", version: u16, registry hash: u64, chunk size: u16, chunk count: u32,
+//   per chunk: x: i64, y: i64, layer mask: u8 (bit n is `TileLayer::ALL[n]`),
+//   per layer in the mask: run count: u16, runs of (count: u16, tile id: u16)
+// Version 1 has no layer mask, the runs are the main layer.
+fn encode_binary(registry: &TileRegistry, chunks: &[((isize, isize), ChunkLayers)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&registry.hash().to_le_bytes());
+    out.extend_from_slice(&(CHUNK_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+
+    for &((cx, cy), ref layers) in chunks {
+        out.extend_from_slice(&(cx as i64).to_le_bytes());
+        out.extend_from_slice(&(cy as i64).to_le_bytes());
+        write_layers(&mut out, layers);
+    }
+
+    out
+}
+
+// Chunk file layout, little endian like the binary levels:
+//   magic "PCHK", version: u16, registry hash: u64, layers like a chunk of a binary level
+pub(crate) fn encode_chunk(layers: &ChunkLayers, registry: &TileRegistry) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(CHUNK_MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&registry.hash().to_le_bytes());
+    write_layers(&mut out, layers);
+    out
+}
+
+pub(crate) fn decode_chunk(
+    bytes: &[u8],
+    coords: (isize, isize),
+    registry: &TileRegistry,
+) -> Result<ChunkLayers, LevelError> {
+    if !bytes.starts_with(CHUNK_MAGIC) {
+        return Err(LevelError::NotALevel);
+    }
+    let mut reader = ByteReader { bytes: &bytes[CHUNK_MAGIC.len()..], pos: 0 };
+
+    let version = reader.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(LevelError::UnsupportedVersion(version));
+    }
+    check_registry(reader.u64()?, registry)?;
+
+    let layers = read_layers(&mut reader, coords, version)?;
+    check_chunk(coords, &layers, registry, &mut HashSet::new())?;
+    Ok(layers)
+}
+
+/// Reads little endian numbers and fails on a truncated file
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], LevelError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| LevelError::Corrupt(format!("file ends early, at byte {}", self.pos + MAGIC.len())))?;
+        self.pos += N;
+
+        let mut array = [0; N];
+        array.copy_from_slice(bytes);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, LevelError> {
+        self.take().map(u8::from_le_bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, LevelError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, LevelError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, LevelError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, LevelError> {
+        self.take().map(i64::from_le_bytes)
+    }
+}
+
+fn check_registry(hash: u64, registry: &TileRegistry) -> Result<(), LevelError> {
+    if hash != registry.hash() {
+        return Err(LevelError::RegistryMismatch {
+            expected: registry.hash(),
+            found: hash,
+        });
+    }
+    Ok(())
+}
+
+fn check_version(version: u16) -> Result<(), LevelError> {
+    if !(OLDEST_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(LevelError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn check_chunk(
+    coords: (isize, isize),
+    layers: &ChunkLayers,
+    registry: &TileRegistry,
+    seen: &mut HashSet<(isize, isize)>,
+) -> Result<(), LevelError> {
+    for (layer, cells) in TileLayer::ALL.iter().zip(layers.iter()).filter(|(_, cells)| !cells.is_empty()) {
+        if cells.len() != CELLS_PER_CHUNK {
+            return Err(LevelError::Corrupt(format!(
+                "{} layer of chunk {:?} has {} cells instead of {}",
+                layer.name(),
+                coords,
+                cells.len(),
+                CELLS_PER_CHUNK
+            )));
+        }
+        if let Some(unknown) = cells.iter().find(|&&cell| !registry.contains(cell)) {
+            return Err(LevelError::Corrupt(format!("chunk {:?} contains unknown tile id {}", coords, unknown.0)));
+        }
+    }
+    if !seen.insert(coords) {
+        return Err(LevelError::Corrupt(format!("chunk {:?} is stored twice", coords)));
+    }
+    Ok(())
+}
+
+fn read_runs(reader: &mut ByteReader, coords: (isize, isize)) -> Result<Vec<TileId>, LevelError> {
+    let run_count = reader.u16()?;
+    let mut cells = Vec::with_capacity(CELLS_PER_CHUNK);
+    for _ in 0..run_count {
+        let count = reader.u16()? as usize;
+        let tile = TileId(reader.u16()?);
+        if cells.len() + count > CELLS_PER_CHUNK {
+            return Err(LevelError::Corrupt(format!("runs of chunk {:?} overflow the chunk", coords)));
+        }
+        cells.extend(std::iter::repeat(tile).take(count));
+    }
+    Ok(cells)
+}
+
+fn read_layers(reader: &mut ByteReader, coords: (isize, isize), version: u16) -> Result<ChunkLayers, LevelError> {
+    let mut layers = ChunkLayers::default();
+    if version == 1 {
+        layers[TileLayer::Main.index()] = read_runs(reader, coords)?;
+        return Ok(layers);
+    }
+
+    let mask = reader.u8()?;
+    if mask >> LAYER_COUNT != 0 {
+        return Err(LevelError::Corrupt(format!("chunk {:?} has unknown layers {:08b}", coords, mask)));
+    }
+    for layer in TileLayer::ALL.iter().filter(|layer| mask & 1 << layer.index() != 0) {
+        layers[layer.index()] = read_runs(reader, coords)?;
+    }
+    Ok(layers)
+}
+
+/// Decodes everything after the magic
+fn decode_binary(bytes: &[u8], registry: &TileRegistry) -> Result<LevelChunks, LevelError> {
+    let mut reader = ByteReader { bytes, pos: 0 };
+
+    let version = reader.u16()?;
+    check_version(version)?;
+    check_registry(reader.u64()?, registry)?;
+
+    let chunk_size = reader.u16()?;
+    if chunk_size as isize != CHUNK_SIZE {
+        return Err(LevelError::Corrupt(format!("chunk size is {} instead of {}", chunk_size, CHUNK_SIZE)));
+    }
+
+    let chunk_count = reader.u32()?;
+    let mut seen = HashSet::new();
+    let mut chunks = vec![];
+
+    for _ in 0..chunk_count {
+        let coords = (reader.i64()? as isize, reader.i64()? as isize);
+        let layers = read_layers(&mut reader, coords, version)?;
+        check_chunk(coords, &layers, registry, &mut seen)?;
+        chunks.push((coords, layers));
+    }
+
+    if reader.pos != bytes.len() {
+        return Err(LevelError::Corrupt(format!("{} unexpected bytes after the last chunk", bytes.len() - reader.pos)));
+    }
+
+    Ok(chunks)
+}
+
+// Text layout, `#` starts a comment line:
+//   platformer-level <version>
+//   registry <hash as hex>
+//   chunk <x> <y>
+//   per stored layer: layer <name>, <CHUNK_SIZE lines of CHUNK_SIZE tile ids>
+// Version 1 has no layer lines, the tile ids after the chunk line are the main layer.
+fn encode_text(registry: &TileRegistry, chunks: &[((isize, isize), ChunkLayers)]) -> String {
+    let mut out = format!("{} {}\nregistry {:016x}\n", TEXT_MAGIC, FORMAT_VERSION, registry.hash());
+    for tile in registry.tiles() {
+        out += &format!("# {} = {}\n", tile.id.0, tile.name);
+    }
+
+    for ((cx, cy), layers) in chunks {
+        out += &format!("\nchunk {} {}\n", cx, cy);
+        for (layer, cells) in TileLayer::ALL.iter().zip(layers.iter()).filter(|(_, cells)| !cells.is_empty()) {
+            out += &format!("layer {}\n", layer.name());
+            for row in cells.chunks(CHUNK_SIZE as usize) {
+                let row: Vec<String> = row.iter().map(|cell| cell.0.to_string()).collect();
+                out += &row.join(" ");
+                out += "\n";
+            }
+        }
+    }
+
+    out
+}
+
+fn corrupt_line(line: usize, msg: &str) -> LevelError {
+    LevelError::Corrupt(format!("line {}: {}", line, msg))
+}
+
+fn decode_text(text: &str, registry: &TileRegistry) -> Result<LevelChunks, LevelError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+
+    let mut header_field = |name: &str| -> Result<(usize, String), LevelError> {
+        let (line_nr, line) = lines.next().ok_or_else(|| LevelError::Corrupt(format!("missing '{}' line", name)))?;
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [key, value] if key == name => Ok((line_nr, value.to_owned())),
+            _ => Err(corrupt_line(line_nr, &format!("expected '{} <value>'", name))),
+        }
+    };
+
+    let (line_nr, version) = header_field(TEXT_MAGIC)?;
+    let version = version.parse().map_err(|_| corrupt_line(line_nr, "invalid version"))?;
+    check_version(version)?;
+    let (line_nr, hash) = header_field("registry")?;
+    let hash = u64::from_str_radix(&hash, 16).map_err(|_| corrupt_line(line_nr, "invalid registry hash"))?;
+    check_registry(hash, registry)?;
+
+    let mut seen = HashSet::new();
+    let mut chunks = vec![];
+
+    while let Some((line_nr, line)) = lines.next() {
+        let coords = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["chunk", x, y] => match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => (x, y),
+                _ => return Err(corrupt_line(line_nr, "invalid chunk coords")),
+            },
+            _ => return Err(corrupt_line(line_nr, "expected 'chunk <x> <y>'")),
+        };
+
+        let mut layers = ChunkLayers::default();
+        if version == 1 {
+            layers[TileLayer::Main.index()] = text_rows(&mut lines, coords)?;
+        }
+        while let Some(&(line_nr, line)) = lines.peek() {
+            let layer = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["layer", name] if version > 1 => {
+                    TileLayer::by_name(name).ok_or_else(|| corrupt_line(line_nr, "unknown layer"))?
+                }
+                _ => break,
+            };
+            lines.next();
+
+            if !layers[layer.index()].is_empty() {
+                return Err(corrupt_line(line_nr, "layer is stored twice"));
+            }
+            layers[layer.index()] = text_rows(&mut lines, coords)?;
+        }
+
+        check_chunk(coords, &layers, registry, &mut seen)?;
+        chunks.push((coords, layers));
+    }
+
+    Ok(chunks)
+}
+
+/// The `CHUNK_SIZE` rows of tile ids of a layer
+fn text_rows<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    coords: (isize, isize),
+) -> Result<Vec<TileId>, LevelError> {
+    let mut cells = Vec::with_capacity(CELLS_PER_CHUNK);
+    for _ in 0..CHUNK_SIZE {
+        let (line_nr, line) = lines
+            .next()
+            .ok_or_else(|| LevelError::Corrupt(format!("chunk {:?} ends early", coords)))?;
+
+        let row = line
+            .split_whitespace()
+            .map(|id| id.parse().map(TileId))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| corrupt_line(line_nr, "invalid tile id"))?;
+        if row.len() != CHUNK_SIZE as usize {
+            return Err(corrupt_line(line_nr, &format!("expected {} tile ids", CHUNK_SIZE)));
+        }
+        cells.extend(row);
+    }
+    Ok(cells)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::level::{decode, encode, LevelError, LevelFormat};
+    use crate::tiles::{test_registry, TileRegistry};
+    use crate::world::{TileLayer, Tilemap};
+    use std::sync::Arc;
+
+    fn test_level() -> Tilemap {
+        let mut tiles = Tilemap::new(test_registry());
+        let stone = tiles.registry().id_by_name("stone").unwrap();
+        let platform = tiles.registry().id_by_name("platform").unwrap();
+        let wall = tiles.registry().id_by_name("stone_wall").unwrap();
+        for x in -20..20 {
+            tiles.set_cell(x, 3, stone);
+        }
+        tiles.set_cell(5, -7, platform);
+        tiles.set_layer_cell(TileLayer::Background, 4, 2, wall);
+        tiles.set_layer_cell(TileLayer::Foreground, -3, 2, platform);
+        tiles
+    }
+
+    #[test]
+    fn test_level_round_trip() {
+        let level = test_level();
+
+        for &format in [LevelFormat::Binary, LevelFormat::Text].iter() {
+            let loaded = decode(&encode(&level, format).unwrap(), test_registry()).expect("failed to load saved level");
+
+            assert_eq!(loaded.chunks_stored(), 5, "{:?}", format);
+            for &layer in TileLayer::ALL.iter() {
+                for y in -20..20 {
+                    for x in -30..30 {
+                        assert_eq!(
+                            loaded.get_layer_cell(layer, x, y),
+                            level.get_layer_cell(layer, x, y),
+                            "{:?} {:?} at ({}, {})",
+                            format,
+                            layer,
+                            x,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_level_errors() {
+        let level = test_level();
+        let binary = encode(&level, LevelFormat::Binary).unwrap();
+
+        assert!(matches!(decode(b"hello", test_registry()), Err(LevelError::NotALevel)));
+        assert!(matches!(decode(&binary[..binary.len() - 3], test_registry()), Err(LevelError::Corrupt(_))));
+
+        let mut newer = binary.clone();
+        newer[4] = 99;
+        assert!(matches!(decode(&newer, test_registry()), Err(LevelError::UnsupportedVersion(99))));
+
+        let other_registry = Arc::new(
+            TileRegistry::from_toml("atlas_size = 1\n[[tile]]\nid = 1\nname = \"dirt\"\natlas_index = 0\nsolidity = \"solid\"")
+                .unwrap(),
+        );
+        for &format in [LevelFormat::Binary, LevelFormat::Text].iter() {
+            let result = decode(&encode(&level, format).unwrap(), other_registry.clone());
+            assert!(matches!(result, Err(LevelError::RegistryMismatch { .. })), "{:?}", format);
+        }
+
+        let text = String::from_utf8(encode(&level, LevelFormat::Text).unwrap()).unwrap();
+        let broken = text.replacen("0 0 0", "0 x 0", 1);
+        assert!(matches!(decode(broken.as_bytes(), test_registry()), Err(LevelError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_level_version_1_is_the_main_layer() {
+        let registry = test_registry();
+        let mut text = format!("platformer-level 1\nregistry {:016x}\nchunk 0 0\n", registry.hash());
+        for y in 0..16 {
+            let row: Vec<&str> = (0..16).map(|x| if x == y { "1" } else { "0" }).collect();
+            text += &row.join(" ");
+            text += "\n";
+        }
+
+        let loaded = decode(text.as_bytes(), registry).expect("failed to load version 1 level");
+        assert_eq!(loaded.get_cell(3, 3).0, 1);
+        assert!(loaded.get_cell(3, 4).is_empty());
+        assert!(loaded.get_layer_cell(TileLayer::Background, 3, 3).is_empty());
+    }
+}
@@ -1,5 +1,4 @@
 #![feature(assoc_int_consts)]
-#![feature(new_uninit)]
 
 use ggez::conf::{WindowMode, WindowSetup};
 use ggez::event::{self, EventHandler, KeyCode, KeyMods, MouseButton};
@@ -15,12 +14,28 @@ mod player;
 mod utils;
 mod world;
 mod server;
+mod client;
 mod game;
 mod cam;
 mod networking;
+mod simulation;
+mod timestep;
+mod tiles;
+mod level;
+mod tiled;
+mod editor;
+mod worldgen;
+mod streaming;
 
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use crate::client::Client;
 use crate::game::Game;
+use crate::tiles::TileRegistry;
+use crate::simulation::Simulation;
+use crate::streaming::ChunkStore;
+use crate::world::Tilemap;
 
 pub static SHOULD_TERMINATE: AtomicBool = AtomicBool::new(false);
 
@@ -33,7 +48,122 @@ fn main() {
         std::path::PathBuf::from("./resources")
     };
 
-    let server_handle = std::thread::spawn(server::start);
+    let registry = match TileRegistry::load(&resource_dir.join("tiles.toml")) {
+        Ok(registry) => Arc::new(registry),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    let args: Vec<String> = std::env::args().collect();
+
+    let level_arg = args.iter().position(|arg| arg == "--level").and_then(|idx| args.get(idx + 1));
+    let level_path = level_arg.map(std::path::Path::new);
+
+    let seed_arg = args.iter().position(|arg| arg == "--seed").and_then(|idx| args.get(idx + 1));
+    let seed = match seed_arg.map(|seed| seed.parse::<u64>()) {
+        Some(Ok(seed)) => Some(seed),
+        Some(Err(e)) => {
+            eprintln!("Invalid seed: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let stream_radius_arg = args.iter().position(|arg| arg == "--stream-radius").and_then(|idx| args.get(idx + 1));
+    let stream_radius = match stream_radius_arg.map(|radius| radius.parse::<isize>()) {
+        Some(Ok(radius)) => radius,
+        Some(Err(e)) => {
+            eprintln!("Invalid stream radius: {}", e);
+            return;
+        }
+        None => streaming::DEFAULT_ACTIVE_RADIUS,
+    };
+
+    let mode_arg = args.iter().position(|arg| arg == "--mode").and_then(|idx| args.get(idx + 1));
+    let mode = match mode_arg.map(String::as_str) {
+        None | Some("local") => Mode::Local,
+        Some("host") => Mode::Host,
+        Some("join") => Mode::Join,
+        Some(other) => {
+            eprintln!("Unknown mode {}, expected local, host or join", other);
+            return;
+        }
+    };
+
+    let address_arg = args.iter().position(|arg| arg == "--address").and_then(|idx| args.get(idx + 1));
+    let address = address_arg.map_or(server::DEFAULT_ADDRESS, String::as_str);
+
+    let name_arg = args.iter().position(|arg| arg == "--name").and_then(|idx| args.get(idx + 1));
+    let name = name_arg.map_or("player", String::as_str);
+
+    let world = World {
+        registry,
+        level_path: level_path.map(|path| path.to_owned()),
+        seed,
+        stream_radius,
+    };
+    // the editor saves imported maps in our own format
+    let save_path = match level_path {
+        Some(path) if !is_tiled_map(path) => path.to_owned(),
+        Some(path) => path.with_extension("lvl"),
+        None => std::path::PathBuf::from("level.lvl"),
+    };
+
+    if args.iter().any(|arg| arg == "--headless") {
+        match mode {
+            Mode::Local => match world.load() {
+                Ok(sim) => simulation::run_headless(1.0 / timestep::DEFAULT_TICK_RATE as f32, sim),
+                Err(e) => eprintln!("{}", e),
+            },
+            // a dedicated server
+            Mode::Host => match (server::bind(address), world.load()) {
+                (Ok(listener), Ok(sim)) => server::start(listener, sim),
+                (Err(e), _) => eprintln!("Can't host on {}: {}", address, e),
+                (_, Err(e)) => eprintln!("{}", e),
+            },
+            Mode::Join => eprintln!("Joining a server needs a window"),
+        }
+        return;
+    }
+
+    // bound here, so our own client can connect right away
+    let server_handle = match mode {
+        Mode::Host => match server::bind(address) {
+            Ok(listener) => {
+                // the simulation isn't Send, so the server loads its own copy of the world
+                let world = world.clone();
+                Some(std::thread::spawn(move || match world.load() {
+                    Ok(sim) => server::start(listener, sim),
+                    Err(e) => eprintln!("Server can't start: {}", e),
+                }))
+            }
+            Err(e) => {
+                eprintln!("Can't host on {}: {}", address, e);
+                return;
+            }
+        },
+        Mode::Local | Mode::Join => None,
+    };
+
+    let started = match mode {
+        Mode::Local => world.load().map(|sim| (sim, None)),
+        Mode::Host | Mode::Join => Client::connect(address, name)
+            .map(|client| (world.empty(), Some(client)))
+            .map_err(|e| format!("Can't connect to {}: {}", address, e)),
+    };
+    let (sim, client) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            eprintln!("{}", e);
+            SHOULD_TERMINATE.store(true, Ordering::Relaxed);
+            if let Some(handle) = server_handle {
+                let _ = handle.join();
+            }
+            return;
+        }
+    };
 
     // Make a Context and an EventLoop.
     let (mut ctx, mut event_loop) = ContextBuilder::new("Game", "lokmeinmatz")
@@ -46,7 +176,7 @@ fn main() {
     // Create an instance of your event handler.
     // Usually, you should provide it with the Context object
     // so it can load resources like images during setup.
-    let mut my_game = game::Game::new(&mut ctx);
+    let mut my_game = game::Game::new(&mut ctx, sim, save_path, client);
 
     // Run!
     match event::run(&mut ctx, &mut event_loop, &mut my_game) {
@@ -55,9 +185,77 @@ fn main() {
     }
 
     SHOULD_TERMINATE.store(true, Ordering::Relaxed);
-    server_handle.join();
+    if let Some(handle) = server_handle {
+        let _ = handle.join();
+    }
+}
+
+/// Where the game gets its world from
+enum Mode {
+    /// Simulates the world itself, without any networking
+    Local,
+    /// Runs a server in the background and joins it
+    Host,
+    /// Joins the server at `--address`
+    Join,
+}
+
+
+/// Where a simulation gets its tiles from, so the game and the server can each load their own
+#[derive(Clone)]
+struct World {
+    registry: Arc<TileRegistry>,
+    level_path: Option<PathBuf>,
+    seed: Option<u64>,
+    stream_radius: isize,
 }
 
+impl World {
+    /// The level file if there is one, otherwise a generated world for a seed or the test level
+    fn load(&self) -> Result<Simulation, String> {
+        let registry = self.registry.clone();
+        let sim = match &self.level_path {
+            Some(path) => {
+                let sim = if is_tiled_map(path) {
+                    tiled::import(path, registry).map(Simulation::from_tiled).map_err(|e| e.to_string())
+                } else {
+                    Tilemap::load(path, registry).map(Simulation::with_tiles).map_err(|e| e.to_string())
+                };
+                sim.map_err(|e| format!("Failed to load level {}: {}", path.display(), e))?
+            }
+            None => match self.seed {
+                Some(seed) => Simulation::generated(registry, seed),
+                None => Simulation::new(registry),
+            },
+        };
+
+        self.enable_streaming(&sim);
+        Ok(sim)
+    }
+
+    /// A world without any tiles, for clients that receive the chunks from the server
+    fn empty(&self) -> Simulation {
+        let sim = Simulation::with_tiles(Tilemap::new(self.registry.clone()));
+        self.enable_streaming(&sim);
+        sim
+    }
+
+    fn enable_streaming(&self, sim: &Simulation) {
+        // without a store modified chunks simply stay loaded
+        let chunk_store = match ChunkStore::new() {
+            Ok(store) => Some(store),
+            Err(e) => {
+                eprintln!("Chunks can't be unloaded to disk: {}", e);
+                None
+            }
+        };
+        sim.tiles.borrow_mut().enable_streaming(self.stream_radius, chunk_store);
+    }
+}
+
+fn is_tiled_map(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "tmx" || ext == "tmj" || ext == "json")
+}
 
 pub trait DebugDrawable {
     fn debug_draw_screenspace(&mut self, ctx: &mut Context, game: &Game) -> GameResult<()> {
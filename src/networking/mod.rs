@@ -1 +1,18 @@
-pub(crate) mod packets;
\ No newline at end of file
+pub(crate) mod packets;
+
+use async_std::prelude::*;
+use async_std::{net, task};
+use std::sync::mpsc;
+
+/// Writes encoded packets to the stream in order, until the sender is dropped or the connection fails.
+/// Runs on its own thread, so a slow peer never blocks the simulation.
+pub(crate) fn write_packets(mut stream: net::TcpStream, packets: mpsc::Receiver<Vec<u8>>) {
+    for bytes in packets {
+        if task::block_on(stream.write_all(&bytes)).is_err() {
+            break;
+        }
+    }
+
+    // also ends the reader of the connection
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
@@ -4,41 +4,179 @@ use async_std::prelude::*;
 #[derive(Clone, Copy, Debug)]
 enum PacketType {
     EntitiesFrameData = 0x01,
-    ChunkData = 0x02
+    ChunkData = 0x02,
+    PlayerInput = 0x03,
+    Join = 0x04,
+    Leave = 0x05,
+    Chat = 0x06
 }
 
 impl TryFrom<u8> for PacketType {
-    type Error = ();
+    type Error = NetError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         Ok(match value {
             x if x == PacketType::EntitiesFrameData as u8 => PacketType::EntitiesFrameData,
             x if x == PacketType::ChunkData as u8 => PacketType::ChunkData,
-            _ => return Err(())
+            x if x == PacketType::PlayerInput as u8 => PacketType::PlayerInput,
+            x if x == PacketType::Join as u8 => PacketType::Join,
+            x if x == PacketType::Leave as u8 => PacketType::Leave,
+            x if x == PacketType::Chat as u8 => PacketType::Chat,
+            _ => return Err(NetError::UnknownType(value))
         })
     }
 }
 
-#[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct EntityNetworkData {
-    id: u64,
-    pos: (f32, f32),
-    vel: (f32, f32)
+    pub id: u64,
+    pub pos: (f32, f32),
+    pub vel: (f32, f32)
+}
+
+/// Input of one player for one simulation tick
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PlayerInput {
+    pub tick: u64,
+    /// -1 is left, 1 is right
+    pub move_x: f32,
+    pub jump: bool,
+    pub drop_through: bool
 }
 
+const INPUT_JUMP: u8 = 1 << 0;
+const INPUT_DROP_THROUGH: u8 = 1 << 1;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Packet {
-    EntitiesFrameData(Box<[EntityNetworkData]>)
+    EntitiesFrameData(Box<[EntityNetworkData]>),
+    /// A chunk in the chunk file format of `level`, the receiver checks it against its tile registry
+    ChunkData { x: i64, y: i64, chunk: Vec<u8> },
+    PlayerInput { player_id: u64, input: PlayerInput },
+    Join { player_id: u64, name: String },
+    Leave { player_id: u64 },
+    Chat { player_id: u64, message: String }
+}
+
+use async_std::io;
+use std::convert::TryFrom;
+use std::fmt;
 
+/// Length of the header in front of every payload
+pub const HEADER_SIZE: usize = 8;
+
+/// Sent with every Join, peers with another version can't talk to each other
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Longest payload the decoder accepts, so a bad header can't make it allocate arbitrary amounts.
+/// Fits the longest string and a few thousand entities.
+pub const MAX_PAYLOAD_SIZE: usize = 128 * 1024;
+
+/// Bytes of one entity in an EntitiesFrameData payload
+const ENTITY_SIZE: usize = 8 + 4 * 4;
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    UnknownType(u8),
+    /// The header doesn't contain 'PKG', so the stream isn't in sync with the packets
+    BadSignature,
+    /// The header announces a payload longer than `MAX_PAYLOAD_SIZE`
+    Oversized(usize),
+    /// The payload ends before all fields of its packet type are read
+    Truncated,
+    /// The payload has all fields, but they don't make sense, e.g. an entity count that doesn't match its length
+    Malformed(&'static str),
+    VersionMismatch { expected: u16, found: u16 },
 }
 
-const MAX_PAYLOAD_SIZE: usize = 4;
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Io(e) => write!(f, "connection failed: {}", e),
+            NetError::UnknownType(ptype) => write!(f, "unknown packet type {:#04x}", ptype),
+            NetError::BadSignature => write!(f, "packet signature invalid"),
+            NetError::Oversized(len) => {
+                write!(f, "payload of {} bytes is larger than the maximum of {}", len, MAX_PAYLOAD_SIZE)
+            }
+            NetError::Truncated => write!(f, "payload too short"),
+            NetError::Malformed(msg) => write!(f, "malformed payload: {}", msg),
+            NetError::VersionMismatch { expected, found } => {
+                write!(f, "peer uses protocol version {}, expected {}", found, expected)
+            }
+        }
+    }
+}
 
-use async_std::net;
-use std::convert::TryFrom;
-use std::mem::MaybeUninit;
+impl std::error::Error for NetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes a packet with its header, the counterpart of `decode_next_packet`
+pub fn encode_packet(packet: &Packet) -> Vec<u8> {
+    let mut payload = PayloadWriter(vec![]);
+
+    let ptype = match packet {
+        Packet::EntitiesFrameData(entities) => {
+            payload.u32(entities.len() as u32);
+            for entity in entities.iter() {
+                payload.u64(entity.id);
+                payload.f32(entity.pos.0);
+                payload.f32(entity.pos.1);
+                payload.f32(entity.vel.0);
+                payload.f32(entity.vel.1);
+            }
+            PacketType::EntitiesFrameData
+        }
+        Packet::ChunkData { x, y, chunk } => {
+            payload.i64(*x);
+            payload.i64(*y);
+            payload.0.extend_from_slice(chunk);
+            PacketType::ChunkData
+        }
+        Packet::PlayerInput { player_id, input } => {
+            payload.u64(*player_id);
+            payload.u64(input.tick);
+            payload.f32(input.move_x);
+            let mut buttons = 0;
+            if input.jump {
+                buttons |= INPUT_JUMP;
+            }
+            if input.drop_through {
+                buttons |= INPUT_DROP_THROUGH;
+            }
+            payload.0.push(buttons);
+            PacketType::PlayerInput
+        }
+        Packet::Join { player_id, name } => {
+            payload.0.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            payload.u64(*player_id);
+            payload.string(name);
+            PacketType::Join
+        }
+        Packet::Leave { player_id } => {
+            payload.u64(*player_id);
+            PacketType::Leave
+        }
+        Packet::Chat { player_id, message } => {
+            payload.u64(*player_id);
+            payload.string(message);
+            PacketType::Chat
+        }
+    };
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + payload.0.len());
+    out.push(ptype as u8);
+    out.extend_from_slice(b"PKG");
+    out.extend_from_slice(&(payload.0.len() as u32).to_be_bytes());
+    out.extend_from_slice(&payload.0);
+    out
+}
 
 /// Decodes the next network packet
 /// Structure:
@@ -46,41 +184,261 @@ use std::mem::MaybeUninit;
 ///  +------+------+------------+- ... ---+
 ///  | type | 'PKG'| packet_len | payload |
 ///
-/// EntitiesFrameData payload:
-///  +--------------+--------- ... ----------------+
-///  | 32b enti_cnt | enti_cnt * EntityNetworkData |
-pub async fn decode_next_packet(header_buf: &[u8; 8], stream: &mut net::TcpStream) -> Result<Packet, &'static str> {
+/// All fields are big endian, strings are prefixed with their length as u16.
+/// Payloads longer than `MAX_PAYLOAD_SIZE` are rejected before they are read.
+///  EntitiesFrameData: u32 count | count * (u64 id | f32 pos x | f32 pos y | f32 vel x | f32 vel y)
+///  ChunkData:   i64 x | i64 y | chunk file
+///  PlayerInput: u64 player_id | u64 tick | f32 move_x | u8 buttons (1 = jump, 2 = drop through)
+///  Join:        u16 protocol version | u64 player_id | string name
+///  Leave:       u64 player_id
+///  Chat:        u64 player_id | string message
+pub async fn decode_next_packet<R: io::Read + Unpin>(header_buf: &[u8; HEADER_SIZE], stream: &mut R) -> Result<Packet, NetError> {
 
-    let ptype = PacketType::try_from(header_buf[0]).map_err(|_| "unknown packet type")?;
+    let ptype = PacketType::try_from(header_buf[0])?;
 
     if &header_buf[1..4] != b"PKG" {
-        return Err("Packet signature invalid");
+        return Err(NetError::BadSignature);
     }
 
 
     let payload_len = u32::from_be_bytes(
         [header_buf[4], header_buf[5], header_buf[6], header_buf[7]]) as usize;
 
-    assert!(header_buf.len() >= payload_len);
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(NetError::Oversized(payload_len));
+    }
+
+    let mut payload = vec![0u8; payload_len];
+    stream.read_exact(&mut payload).await.map_err(NetError::Io)?;
+    let mut reader = PayloadReader { bytes: &payload, pos: 0 };
 
-    match ptype {
+    let packet = match ptype {
         PacketType::EntitiesFrameData => {
-            // get how long the entity list is
-            let entities_to_sync = {
-                let mut buf = [0u8; 4];
-                stream.read_exact(&mut buf).await.map_err(|_| "Failed to read len of entites to sync")?;
-                u32::from_be_bytes(buf)
-            };
-
-            // allocate empty buffer
-            let mut network_e_data: Box<[MaybeUninit<EntityNetworkData>]> = Box::new_uninit_slice(entities_to_sync as usize);
-
-            unsafe {
-                let net_buffer = std::slice::from_raw_parts_mut(network_e_data.as_mut_ptr() as *mut u8, std::mem::size_of_val(network_e_data.as_ref()));
-                stream.read_exact(net_buffer).await.unwrap();
-                return Ok(Packet::EntitiesFrameData(network_e_data.assume_init()))
+            let count = reader.u32()? as usize;
+            if count.checked_mul(ENTITY_SIZE) != Some(payload.len() - 4) {
+                return Err(NetError::Malformed("entity count doesn't match the payload length"));
+            }
+
+            let mut entities = Vec::with_capacity(count);
+            for _ in 0..count {
+                entities.push(EntityNetworkData {
+                    id: reader.u64()?,
+                    pos: (reader.f32()?, reader.f32()?),
+                    vel: (reader.f32()?, reader.f32()?)
+                });
+            }
+            Packet::EntitiesFrameData(entities.into_boxed_slice())
+        }
+        PacketType::ChunkData => Packet::ChunkData {
+            x: reader.i64()?,
+            y: reader.i64()?,
+            chunk: reader.rest().to_vec()
+        },
+        PacketType::PlayerInput => {
+            let player_id = reader.u64()?;
+            let tick = reader.u64()?;
+            let move_x = reader.f32()?;
+            let buttons = reader.u8()?;
+            Packet::PlayerInput {
+                player_id,
+                input: PlayerInput {
+                    tick,
+                    move_x,
+                    jump: buttons & INPUT_JUMP != 0,
+                    drop_through: buttons & INPUT_DROP_THROUGH != 0
+                }
+            }
+        }
+        PacketType::Join => {
+            let version = u16::from_be_bytes(reader.take()?);
+            if version != PROTOCOL_VERSION {
+                return Err(NetError::VersionMismatch { expected: PROTOCOL_VERSION, found: version });
+            }
+            Packet::Join {
+                player_id: reader.u64()?,
+                name: reader.string()?
+            }
+        }
+        PacketType::Leave => Packet::Leave { player_id: reader.u64()? },
+        PacketType::Chat => Packet::Chat {
+            player_id: reader.u64()?,
+            message: reader.string()?
+        }
+    };
+
+    if reader.pos != payload.len() {
+        return Err(NetError::Malformed("unexpected bytes after the packet"));
+    }
+
+    Ok(packet)
+}
+
+/// Appends big endian fields
+struct PayloadWriter(Vec<u8>);
+
+impl PayloadWriter {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Longer strings are cut off at a char boundary
+    fn string(&mut self, value: &str) {
+        let mut len = value.len().min(u16::MAX as usize);
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.0.extend_from_slice(&(len as u16).to_be_bytes());
+        self.0.extend_from_slice(&value.as_bytes()[..len]);
+    }
+}
+
+/// Reads big endian fields of a payload
+struct PayloadReader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> PayloadReader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], NetError> {
+        let bytes = self.bytes.get(self.pos..self.pos + N).ok_or(NetError::Truncated)?;
+        self.pos += N;
+
+        let mut array = [0; N];
+        array.copy_from_slice(bytes);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, NetError> {
+        self.take().map(u8::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, NetError> {
+        self.take().map(u32::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, NetError> {
+        self.take().map(u64::from_be_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, NetError> {
+        self.take().map(i64::from_be_bytes)
+    }
+
+    fn f32(&mut self) -> Result<f32, NetError> {
+        self.take().map(f32::from_be_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, NetError> {
+        let len = u16::from_be_bytes(self.take()?) as usize;
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or(NetError::Truncated)?;
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| NetError::Malformed("string is not valid utf-8"))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::networking::packets::{decode_next_packet, encode_packet, EntityNetworkData, NetError, Packet, PlayerInput, HEADER_SIZE, MAX_PAYLOAD_SIZE, PROTOCOL_VERSION};
+    use async_std::task;
+    use std::convert::TryInto;
+
+    fn round_trip(packet: Packet) {
+        let bytes = encode_packet(&packet);
+        let header: [u8; HEADER_SIZE] = bytes[..HEADER_SIZE].try_into().unwrap();
+        let mut payload = &bytes[HEADER_SIZE..];
+
+        let decoded = task::block_on(decode_next_packet(&header, &mut payload)).expect("failed to decode");
+        assert_eq!(decoded, packet);
+        assert!(payload.is_empty(), "{} bytes of {:?} left over", payload.len(), packet);
+    }
+
+    #[test]
+    fn test_packet_round_trips() {
+        round_trip(Packet::EntitiesFrameData(Box::new([])));
+        round_trip(Packet::EntitiesFrameData(Box::new([
+            EntityNetworkData { id: 1, pos: (15.0, -2.5), vel: (0.25, 9.81) },
+            EntityNetworkData { id: u64::MAX, pos: (-1e6, 3.0), vel: (0.0, -0.0) },
+        ])));
+        round_trip(Packet::ChunkData { x: -3, y: 7, chunk: b"PCHK chunk bytes".to_vec() });
+        round_trip(Packet::PlayerInput {
+            player_id: 2,
+            input: PlayerInput { tick: 1234, move_x: -1.0, jump: true, drop_through: false },
+        });
+        round_trip(Packet::PlayerInput {
+            player_id: 3,
+            input: PlayerInput { tick: 0, move_x: 0.5, jump: false, drop_through: true },
+        });
+        round_trip(Packet::Join { player_id: 7, name: "Spieler Äöü".to_owned() });
+        round_trip(Packet::Leave { player_id: 7 });
+        round_trip(Packet::Chat { player_id: 7, message: String::new() });
+        round_trip(Packet::Chat { player_id: 8, message: "gg 🎉".to_owned() });
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Packet, NetError> {
+        let header: [u8; HEADER_SIZE] = bytes[..HEADER_SIZE].try_into().unwrap();
+        let mut payload = &bytes[HEADER_SIZE..];
+        task::block_on(decode_next_packet(&header, &mut payload))
+    }
+
+    #[test]
+    fn test_malformed_packets_are_rejected() {
+        let entities = encode_packet(&Packet::EntitiesFrameData(Box::new([
+            EntityNetworkData { id: 1, pos: (0.0, 0.0), vel: (0.0, 0.0) },
+        ])));
+        assert!(decode(&entities).is_ok());
+
+        // a count that claims more entities than the payload holds
+        let mut too_many = entities.clone();
+        too_many[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(decode(&too_many), Err(NetError::Malformed(_))));
+
+        // the declared payload is longer than what is sent
+        assert!(matches!(decode(&entities[..entities.len() - 1]), Err(NetError::Io(_))));
+
+        // the length is checked before anything is read
+        let mut oversized = entities.clone();
+        oversized[4..8].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(decode(&oversized), Err(NetError::Oversized(_))));
+
+        let mut bad_signature = entities.clone();
+        bad_signature[1] = b'X';
+        assert!(matches!(decode(&bad_signature), Err(NetError::BadSignature)));
+
+        let mut unknown_type = entities.clone();
+        unknown_type[0] = 0xff;
+        assert!(matches!(decode(&unknown_type), Err(NetError::UnknownType(0xff))));
+
+        // a payload that ends within the fields of its packet
+        let mut leave = encode_packet(&Packet::Leave { player_id: 1 });
+        leave.pop();
+        leave[4..8].copy_from_slice(&7u32.to_be_bytes());
+        assert!(matches!(decode(&leave), Err(NetError::Truncated)));
+
+        let mut join = encode_packet(&Packet::Join { player_id: 1, name: "a".to_owned() });
+        join[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        match decode(&join) {
+            Err(NetError::VersionMismatch { expected, found }) => {
+                assert_eq!((expected, found), (PROTOCOL_VERSION, PROTOCOL_VERSION + 1))
             }
+            other => panic!("expected a version mismatch, got {:?}", other),
         }
-        _ => unimplemented!()
     }
-}
\ No newline at end of file
+}
@@ -2,10 +2,10 @@ use crate::DebugDrawable;
 use crate::utils::SharedWeak;
 use cgmath::{InnerSpace, Point2, Vector2};
 use ggez::graphics::{Rect, Color, DrawParam};
-use itertools::Itertools;
 use std::borrow::{Borrow, BorrowMut};
 use std::cell::{RefCell, RefMut};
 use std::rc::Rc;
+use std::collections::HashMap;
 use ggez::{Context, GameError, GameResult};
 
 struct CollisionDebugDraw {
@@ -25,46 +25,273 @@ impl DebugDrawable for CollisionDebugDraw {
     }
 }
 
-pub fn step_rb_sim(rbs: &mut Vec<SharedWeak<RigidBody>>, delta_time: f32, frame_drawables: &mut Vec<Box<dyn DebugDrawable>>) {
-    let mut upgraded: Vec<_> = rbs.iter().filter_map(SharedWeak::upgrade).collect();
+/// Advances all rigid bodies by `delta_time` and returns the collision events between bodies
+/// (contacts with tiles are only reported in `RigidBody::contacts`).
+pub fn step_rb_sim(
+    rbs: &mut Vec<SharedWeak<RigidBody>>,
+    tiles: &Tilemap,
+    tracker: &mut ContactTracker,
+    delta_time: f32,
+    frame_drawables: &mut Vec<Box<dyn DebugDrawable>>,
+) -> Vec<CollisionEvent> {
+    let upgraded: Vec<_> = rbs.iter().filter_map(SharedWeak::upgrade).collect();
+
+    // how far every body wants to move this step
+    let motions: Vec<Vector2<f32>> = upgraded
+        .iter()
+        .map(|rb| {
+            let mut rb = (**rb).borrow_mut();
+            rb.prev_top_left = rb.top_left;
+            rb.was_on_ground = rb.contacts.on_ground;
+            rb.contacts.clear();
+            if rb.weight.is_some() {
+                rb.velocity * delta_time
+            } else {
+                rb.velocity *= 0.0;
+                Vector2::new(0.0, 0.0)
+            }
+        })
+        .collect();
+
+    // the broad phase runs on the area each body sweeps over, so fast bodies find everything in their way
+    let swept_bounds: Vec<(Rect, bool)> = upgraded
+        .iter()
+        .zip(&motions)
+        .map(|(rb, motion)| {
+            let rb = (**rb).borrow();
+            let rect = rb.get_transformed_rect();
+            let mut moved = rect;
+            moved.translate(*motion);
+            (rect.combine_with(moved), rb.weight.is_some())
+        })
+        .collect();
+    let mut pairs = broad_phase_pairs(&swept_bounds);
+    pairs.retain(|&(idx_a, idx_b)| {
+        RigidBody::can_collide(&(*upgraded[idx_a]).borrow(), &(*upgraded[idx_b]).borrow())
+    });
+    // triggers only report overlaps, they never push or get pushed
+    let (trigger_pairs, pairs): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|&(idx_a, idx_b)| {
+        (*upgraded[idx_a]).borrow().is_trigger || (*upgraded[idx_b]).borrow().is_trigger
+    });
+
+    // every pair of bodies touching this step, with the normal pointing towards the lower id
+    let mut touching = HashMap::new();
+
+    let mut candidates = vec![vec![]; upgraded.len()];
+    for &(idx_a, idx_b) in &pairs {
+        candidates[idx_a].push(idx_b);
+        candidates[idx_b].push(idx_a);
+    }
+
+    for (idx, motion) in motions.into_iter().enumerate() {
+        let mut rb = (*upgraded[idx]).borrow_mut();
+        if rb.weight.is_none() {
+            continue;
+        }
+
+        // move until the first contact, then slide along it with the rest of the motion
+        // (at most one contact per axis)
+        let mut remaining = motion;
+        for _ in 0..2 {
+            let rect = rb.get_transformed_rect();
+            let contact = candidates[idx]
+                .iter()
+                .filter_map(|other| {
+                    let other_rect = (*upgraded[*other]).borrow().get_transformed_rect();
+                    RigidBody::get_time_of_impact(&rect, remaining, &other_rect).map(|c| (*other, c))
+                })
+                .min_by(|(_, a), (_, b)| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+
+            let (other, contact) = match contact {
+                Some(contact) => contact,
+                None => break,
+            };
+
+            rb.move_against_tiles(remaining * contact.time, tiles);
+            frame_drawables.push(Box::new(CollisionDebugDraw{world_pos: rb.top_left, displacement: contact.normal}));
 
-    for mut rb in &mut upgraded {
-        let mut rb = (**rb).borrow_mut();
-        if rb.weight.is_some() {
-            // add gravity
-            let scaled_vel = rb.velocity * delta_time;
-            rb.top_left += scaled_vel;
+            remaining *= 1. - contact.time;
+            if contact.normal.x != 0. {
+                remaining.x = 0.;
+            } else {
+                remaining.y = 0.;
+            }
+            let mut other = (*upgraded[other]).borrow_mut();
+            ContactTracker::record(&mut touching, &rb, &other, contact.normal);
+            RigidBody::apply_contact_impulse(&mut rb, ContactPartner::Body(&mut other), contact.normal);
+        }
+        rb.move_against_tiles(remaining, tiles);
+        rb.dropping_through = false;
 
-            rb.velocity *= 1. - delta_time * 0.5;
+        rb.velocity *= 1. - delta_time * 0.5;
         // gravity
         rb.velocity.y += delta_time * 9.0;
-        } else {
-            rb.velocity *= 0.0;
-        }
     }
 
-    for (idx_a, idx_b) in (0..upgraded.len()).tuple_combinations::<(_, _)>() {
+    // bodies that overlap anyway (e.g. spawned inside each other) are pushed apart
+    for (idx_a, idx_b) in pairs {
         let mut rb_a: RefMut<RigidBody> = (*upgraded[idx_a]).borrow_mut();
         let mut rb_b: RefMut<RigidBody> = (*upgraded[idx_b]).borrow_mut();
-        if rb_a.weight.is_none() && rb_b.weight.is_none() {
-            continue;
-        };
         if let Some(displacement) =
             RigidBody::get_collision_displacement(rb_a.borrow(), rb_b.borrow())
         {
             //println!("tl {:?} | displ {:?}", rb_a.top_left, displacement);
             frame_drawables.push(Box::new(CollisionDebugDraw{world_pos: rb_a.top_left, displacement: displacement * 2.0}));
+            let normal = least_penetration(displacement);
+            if normal.x != 0. || normal.y != 0. {
+                ContactTracker::record(&mut touching, &rb_a, &rb_b, normal.normalize());
+            }
             RigidBody::resolve_collision(&mut rb_a, &mut rb_b, displacement);
         }
     }
 
+    for (idx_a, idx_b) in trigger_pairs {
+        let rb_a = (*upgraded[idx_a]).borrow();
+        let rb_b = (*upgraded[idx_b]).borrow();
+        if let Some(displacement) = RigidBody::get_collision_displacement(&rb_a, &rb_b) {
+            let normal = least_penetration(displacement);
+            let normal = if normal.x == 0. && normal.y == 0. { normal } else { normal.normalize() };
+            ContactTracker::record(&mut touching, &rb_a, &rb_b, normal);
+        }
+    }
+
+    let events = tracker.update(touching);
+
     let upgraded = crate::utils::map_in_place(upgraded, |e| Rc::downgrade(&e));
 
     std::mem::replace(rbs, upgraded);
+
+    events
+}
+
+/// Keeps only the axis of `displacement` with the smaller penetration
+fn least_penetration(mut displacement: Vector2<f32>) -> Vector2<f32> {
+    if displacement.x.abs() > displacement.y.abs() {
+        displacement.x = 0.;
+    } else {
+        displacement.y = 0.;
+    }
+    displacement
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionEventKind {
+    /// The bodies started touching this step
+    Enter,
+    /// The bodies touched in the last step and still do
+    Stay,
+    /// The bodies touched in the last step but don't anymore
+    Exit,
+}
+
+/// Two bodies touched, reported by every `step_rb_sim`
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionEvent {
+    pub kind: CollisionEventKind,
+    /// `RigidBody::id()` of the first body, always the lower id
+    pub a: u64,
+    /// `RigidBody::id()` of the second body
+    pub b: u64,
+    /// Normal of the contact pointing towards `a`, for `Exit` the one of the last contact
+    pub normal: Vector2<f32>,
+}
+
+/// Remembers which bodies touched in the last step, to tell enter, stay and exit events apart
+#[derive(Debug, Default)]
+pub struct ContactTracker {
+    touching: HashMap<(u64, u64), Vector2<f32>>,
+}
+
+impl ContactTracker {
+    /// Adds a contact of `a` and `b` with `normal` pointing towards `a`
+    fn record(touching: &mut HashMap<(u64, u64), Vector2<f32>>, a: &RigidBody, b: &RigidBody, normal: Vector2<f32>) {
+        if a.id < b.id {
+            touching.insert((a.id, b.id), normal);
+        } else {
+            touching.insert((b.id, a.id), -normal);
+        }
+    }
+
+    /// Compares the contacts of this step to the last one
+    fn update(&mut self, touching: HashMap<(u64, u64), Vector2<f32>>) -> Vec<CollisionEvent> {
+        let mut events: Vec<CollisionEvent> = touching
+            .iter()
+            .map(|(&(a, b), &normal)| {
+                let kind = if self.touching.contains_key(&(a, b)) {
+                    CollisionEventKind::Stay
+                } else {
+                    CollisionEventKind::Enter
+                };
+                CollisionEvent { kind, a, b, normal }
+            })
+            .collect();
+
+        events.extend(
+            self.touching
+                .iter()
+                .filter(|(ids, _)| !touching.contains_key(ids))
+                .map(|(&(a, b), &normal)| CollisionEvent { kind: CollisionEventKind::Exit, a, b, normal }),
+        );
+
+        // hash map order is random, keep the events deterministic
+        events.sort_by_key(|e| (e.a, e.b));
+
+        self.touching = touching;
+        events
+    }
+}
+
+/// Sweep and prune along the x axis over the `(bounds, is_dynamic)` of every body.
+/// Returns the index pairs of all bodies whose x extents overlap, ordered like
+/// `tuple_combinations` would yield them so collisions are resolved in a stable order.
+/// Static bodies are only tested against active dynamic ones, so static-vs-static
+/// pairs are never enumerated.
+fn broad_phase_pairs(bounds: &[(Rect, bool)]) -> Vec<(usize, usize)> {
+    // (left, right, is_dynamic)
+    let extents: Vec<(f32, f32, bool)> = bounds
+        .iter()
+        .map(|(rect, is_dynamic)| (rect.left(), rect.right(), *is_dynamic))
+        .collect();
+
+    let mut order: Vec<usize> = (0..bounds.len()).collect();
+    order.sort_unstable_by(|a, b| {
+        extents[*a].0.partial_cmp(&extents[*b].0).unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut active_dynamic: Vec<usize> = vec![];
+    let mut active_static: Vec<usize> = vec![];
+    let mut pairs = vec![];
+
+    for idx in order {
+        let (left, _, is_dynamic) = extents[idx];
+        active_dynamic.retain(|other| extents[*other].1 >= left);
+        active_static.retain(|other| extents[*other].1 >= left);
+
+        let candidates = active_dynamic
+            .iter()
+            .chain(active_static.iter().filter(|_| is_dynamic));
+        for other in candidates {
+            pairs.push((idx.min(*other), idx.max(*other)));
+        }
+
+        if is_dynamic {
+            active_dynamic.push(idx);
+        } else {
+            active_static.push(idx);
+        }
+    }
+
+    pairs.sort_unstable();
+    pairs
 }
 
 use std::sync::atomic::{AtomicU64, Ordering};
 use crate::game::Game;
+use crate::tiles::TileDef;
+use crate::world::Tilemap;
+
+/// Ledges up to this high are stepped onto at any speed, like the top end of a 22.5° ramp
+pub const MIN_STEP_UP: f32 = 0.2;
 
 static NEXT_RB_ID: AtomicU64 = AtomicU64::new(0);
 
@@ -72,11 +299,114 @@ static NEXT_RB_ID: AtomicU64 = AtomicU64::new(0);
 pub struct RigidBody {
     id: u64,
     top_left: Point2<f32>,
+    /// `top_left` before the last simulation step, used for render interpolation
+    prev_top_left: Point2<f32>,
     dimensions: Vector2<f32>,
     velocity: Vector2<f32>,
     /// If `None`, the rb is solid
     weight: Option<f32>,
+    /// Restitution, 0 absorbs all velocity along the contact normal, 1 bounces back fully
     elasticity: f32,
+    /// Friction coefficient, combined with the one of the touched surface
+    friction: f32,
+    /// Layers this body is on
+    layer: CollisionLayers,
+    /// Layers this body collides with
+    mask: CollisionLayers,
+    /// Triggers report overlaps as collision events but don't push or get pushed
+    is_trigger: bool,
+    /// Ignore one way platforms during the next step
+    dropping_through: bool,
+    /// `contacts.on_ground` of the previous step, to keep the body on the ground on slopes
+    was_on_ground: bool,
+    /// What the body touched during the last simulation step
+    contacts: Contacts,
+}
+
+/// Bitset of collision layers.
+/// Two bodies only collide if each one's mask contains the other one's layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollisionLayers(u32);
+
+impl CollisionLayers {
+    pub const NONE: CollisionLayers = CollisionLayers(0);
+    pub const WORLD: CollisionLayers = CollisionLayers(1 << 0);
+    pub const PLAYER: CollisionLayers = CollisionLayers(1 << 1);
+    pub const ENEMY: CollisionLayers = CollisionLayers(1 << 2);
+    pub const PROJECTILE: CollisionLayers = CollisionLayers(1 << 3);
+    pub const PICKUP: CollisionLayers = CollisionLayers(1 << 4);
+    /// One way platform tiles
+    pub const PLATFORM: CollisionLayers = CollisionLayers(1 << 5);
+    /// Trigger regions of the level, e.g. checkpoints and exits
+    pub const TRIGGER: CollisionLayers = CollisionLayers(1 << 6);
+    pub const ALL: CollisionLayers = CollisionLayers(!0);
+
+    pub fn intersects(self, other: CollisionLayers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn without(self, other: CollisionLayers) -> CollisionLayers {
+        CollisionLayers(self.0 & !other.0)
+    }
+}
+
+impl std::ops::BitOr for CollisionLayers {
+    type Output = CollisionLayers;
+
+    fn bitor(self, rhs: CollisionLayers) -> CollisionLayers {
+        CollisionLayers(self.0 | rhs.0)
+    }
+}
+
+/// Contacts of a body during the last simulation step, so gameplay code can
+/// e.g. only allow jumping while on the ground
+#[derive(Debug, Default, Clone)]
+pub struct Contacts {
+    pub on_ground: bool,
+    pub on_ceiling: bool,
+    pub on_left_wall: bool,
+    pub on_right_wall: bool,
+    /// Normals of all touched surfaces, pointing towards the body
+    pub normals: Vec<Vector2<f32>>,
+}
+
+impl Contacts {
+    fn clear(&mut self) {
+        self.on_ground = false;
+        self.on_ceiling = false;
+        self.on_left_wall = false;
+        self.on_right_wall = false;
+        self.normals.clear();
+    }
+
+    fn record(&mut self, normal: Vector2<f32>) {
+        // y points down, so the ground pushes upwards
+        if normal.y < -0.5 {
+            self.on_ground = true;
+        } else if normal.y > 0.5 {
+            self.on_ceiling = true;
+        } else if normal.x > 0.5 {
+            self.on_left_wall = true;
+        } else if normal.x < -0.5 {
+            self.on_right_wall = true;
+        }
+        self.normals.push(normal);
+    }
+}
+
+/// What a body touches in a contact
+enum ContactPartner<'a> {
+    Body(&'a mut RigidBody),
+    Tile(&'a TileDef),
+}
+
+/// First touch of two bodies found by `RigidBody::get_time_of_impact`
+#[derive(Debug, Clone, Copy)]
+pub struct Contact {
+    /// Fraction of the motion (0 to 1) after which the bodies touch
+    pub time: f32,
+    /// Normal of the surface that was hit, pointing towards the moving body
+    pub normal: Vector2<f32>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -86,6 +416,10 @@ pub enum Axis {
 }
 
 impl RigidBody {
+    pub fn velocity(&self) -> Vector2<f32> {
+        self.velocity
+    }
+
     pub fn velocity_mut(&mut self) -> &mut Vector2<f32> {
         &mut self.velocity
     }
@@ -94,6 +428,27 @@ impl RigidBody {
         self.id
     }
 
+    pub fn can_collide(a: &RigidBody, b: &RigidBody) -> bool {
+        a.mask.intersects(b.layer) && b.mask.intersects(a.layer)
+    }
+
+    pub fn contacts(&self) -> &Contacts {
+        &self.contacts
+    }
+
+    /// Falls through one way platforms in the next step
+    pub fn drop_through(&mut self) {
+        self.dropping_through = true;
+    }
+
+    pub fn set_trigger(&mut self, is_trigger: bool) {
+        self.is_trigger = is_trigger;
+    }
+
+    pub fn set_friction(&mut self, friction: f32) {
+        self.friction = friction;
+    }
+
     pub fn get_collision_displacement(
         a: &RigidBody,
         b: &RigidBody,
@@ -128,57 +483,175 @@ impl RigidBody {
         None
     }
 
+    /// Swept AABB test: moves `a` by `motion` while `b` stays in place.
+    /// Returns when and where they first touch, or `None` if they don't
+    /// (or already overlap, which `get_collision_displacement` handles).
+    pub fn get_time_of_impact(a: &Rect, motion: Vector2<f32>, b: &Rect) -> Option<Contact> {
+        // time interval in which the extents on one axis overlap, `None` if they never do
+        fn axis_overlap_times(a_min: f32, a_max: f32, b_min: f32, b_max: f32, speed: f32) -> Option<(f32, f32)> {
+            if speed > 0. {
+                Some(((b_min - a_max) / speed, (b_max - a_min) / speed))
+            } else if speed < 0. {
+                Some(((b_max - a_min) / speed, (b_min - a_max) / speed))
+            } else if a_max > b_min && a_min < b_max {
+                Some((f32::NEG_INFINITY, f32::INFINITY))
+            } else {
+                None
+            }
+        }
+
+        let (entry_x, exit_x) = axis_overlap_times(a.left(), a.right(), b.left(), b.right(), motion.x)?;
+        let (entry_y, exit_y) = axis_overlap_times(a.top(), a.bottom(), b.top(), b.bottom(), motion.y)?;
+
+        let entry = entry_x.max(entry_y);
+        let exit = exit_x.min(exit_y);
+
+        if entry >= exit || !(0.0..=1.0).contains(&entry) {
+            return None;
+        }
+
+        let normal = if entry_x > entry_y {
+            Vector2::new(-motion.x.signum(), 0.)
+        } else {
+            Vector2::new(0., -motion.y.signum())
+        };
+
+        Some(Contact { time: entry, normal })
+    }
+
+    /// Pushes overlapping bodies apart along the axis of least penetration.
+    /// The displacement is split by inverse mass, so the heavier body moves less
+    /// and static bodies don't move at all.
     pub fn resolve_collision(
         a: &mut RigidBody,
         b: &mut RigidBody,
-        mut displace_a: cgmath::Vector2<f32>,
+        displace_a: cgmath::Vector2<f32>,
     ) {
 
-        let axis_to_fix = if displace_a.x.abs() > displace_a.y.abs() {
-            Axis::Y
-        } else {
-            Axis::X
-        };
+        let displace_a = least_penetration(displace_a);
 
-        //println!("{:?}", axis_to_fix);
-
-        // TODO move based on weight ratio?
-        match axis_to_fix {
-            Axis::X => {
-                a.velocity.x *= -0.5;
-                b.velocity.x *= -0.5;
-                displace_a.y = 0.;
-            },
-            Axis::Y => {
-                a.velocity.y *= -0.5;
-                b.velocity.y *= -0.5;
-                displace_a.x = 0.;
-            }
+        let inv_mass_a = a.inverse_mass();
+        let inv_mass_b = b.inverse_mass();
+        let inv_mass_sum = inv_mass_a + inv_mass_b;
+        if inv_mass_sum == 0. {
+            return;
+        }
+
+        a.top_left += displace_a * (inv_mass_a / inv_mass_sum);
+        b.top_left -= displace_a * (inv_mass_b / inv_mass_sum);
+
+        // only touching, no direction to push
+        if displace_a.x == 0. && displace_a.y == 0. {
+            return;
         }
 
-        if a.weight.is_some() && b.weight.is_some() {
-            // move both half the way
-            displace_a *= 0.5;
-            a.top_left += displace_a;
-            b.top_left -= displace_a;
+        RigidBody::apply_contact_impulse(a, ContactPartner::Body(b), displace_a.normalize());
+    }
+
+    /// Impulse based velocity response for a contact of `a` with `b`,
+    /// `normal` points from `b` towards `a`. The contact is recorded on both sides.
+    /// The impulse is split by inverse mass, the restitution is the larger elasticity of both
+    /// and the friction impulse is limited by the combined friction of both surfaces.
+    fn apply_contact_impulse(a: &mut RigidBody, mut b: ContactPartner, normal: Vector2<f32>) {
+        a.contacts.record(normal);
+
+        let (b_velocity, inv_mass_b, b_elasticity, b_friction) = match &mut b {
+            ContactPartner::Body(b) => {
+                b.contacts.record(-normal);
+                (b.velocity, b.inverse_mass(), b.elasticity, b.friction)
+            }
+            ContactPartner::Tile(tile) => (Vector2::new(0., 0.), 0., 0., tile.friction),
+        };
+        let inv_mass_a = a.inverse_mass();
+        let inv_mass_sum = inv_mass_a + inv_mass_b;
+
+        let relative_velocity = a.velocity - b_velocity;
+        let approach_speed = relative_velocity.dot(normal);
+        // already separating
+        if approach_speed >= 0. || inv_mass_sum == 0. {
+            return;
         }
-        else if a.weight.is_some() {
-            a.top_left += displace_a;
+
+        let restitution = a.elasticity.max(b_elasticity);
+        let normal_impulse = -(1. + restitution) * approach_speed / inv_mass_sum;
+
+        // friction works against the sliding along the surface, at most stopping it
+        let tangent = Vector2::new(-normal.y, normal.x);
+        let max_friction_impulse = (a.friction * b_friction).sqrt() * normal_impulse;
+        let friction_impulse = (-relative_velocity.dot(tangent) / inv_mass_sum)
+            .max(-max_friction_impulse)
+            .min(max_friction_impulse);
+
+        let impulse = normal * normal_impulse + tangent * friction_impulse;
+
+        a.velocity += impulse * inv_mass_a;
+        if let ContactPartner::Body(b) = b {
+            b.velocity -= impulse * inv_mass_b;
         }
-        else if b.weight.is_some() {
-            b.top_left -= displace_a;
+    }
+
+    /// 0 for static bodies, which behave as if infinitely heavy
+    fn inverse_mass(&self) -> f32 {
+        self.weight.map_or(0., |weight| 1. / weight)
+    }
+
+    /// Moves the body by `motion`, first along x then along y, stopping at the first solid tile.
+    /// Because each axis is resolved on its own, seams between adjacent tiles never act as edges
+    /// and bodies slide along floors and walls without catching.
+    /// Unless it moves upwards, the body can step up as far as it moves sideways (up to 45° slopes)
+    /// but at least `MIN_STEP_UP`, and if it stood on the ground it sticks to it when walking down slopes.
+    fn move_against_tiles(&mut self, motion: Vector2<f32>, tiles: &Tilemap) {
+        let mask = if self.dropping_through {
+            self.mask.without(CollisionLayers::PLATFORM)
+        } else {
+            self.mask
+        };
+
+        let step_up = if motion.y >= 0. && motion.x != 0. { motion.x.abs().max(MIN_STEP_UP) } else { 0. };
+
+        match tiles.sweep(self.get_transformed_rect(), Axis::X, motion.x, step_up, mask) {
+            Some((free, cell)) => {
+                self.top_left.x += free;
+                let normal = Vector2::new(-motion.x.signum(), 0.);
+                RigidBody::apply_contact_impulse(self, ContactPartner::Tile(cell), normal);
+            }
+            None => self.top_left.x += motion.x,
         }
 
+        let snap_down = if self.was_on_ground { step_up } else { 0. };
+
+        match tiles.sweep(self.get_transformed_rect(), Axis::Y, motion.y + snap_down, step_up, mask) {
+            Some((free, cell)) => {
+                self.top_left.y += free;
+                let normal = if motion.y >= 0. { Vector2::new(0., -1.) } else { Vector2::new(0., 1.) };
+                RigidBody::apply_contact_impulse(self, ContactPartner::Tile(cell), normal);
+            }
+            None => self.top_left.y += motion.y,
+        }
     }
 
-    pub fn new(top_left: Point2<f32>, dimensions: Vector2<f32>, weight: Option<f32>) -> Self {
+    pub fn new(
+        top_left: Point2<f32>,
+        dimensions: Vector2<f32>,
+        weight: Option<f32>,
+        layer: CollisionLayers,
+        mask: CollisionLayers,
+    ) -> Self {
         RigidBody {
             id: NEXT_RB_ID.fetch_add(1, Ordering::SeqCst),
             top_left,
+            prev_top_left: top_left,
             dimensions,
             velocity: Vector2::new(0.0, 0.0),
             weight,
-            elasticity: 1.0,
+            elasticity: 0.5,
+            friction: 0.2,
+            layer,
+            mask,
+            is_trigger: false,
+            dropping_through: false,
+            was_on_ground: false,
+            contacts: Contacts::default(),
         }
     }
 
@@ -198,17 +671,35 @@ impl RigidBody {
     pub fn get_top_left(&self) -> cgmath::Point2<f32> {
         self.top_left
     }
+
+    /// Moves the body to a state decided elsewhere, e.g. by the server.
+    /// The old position becomes the previous one, so rendering interpolates between the two.
+    pub fn set_state(&mut self, top_left: Point2<f32>, velocity: Vector2<f32>) {
+        self.prev_top_left = self.top_left;
+        self.top_left = top_left;
+        self.velocity = velocity;
+    }
+
+    /// Position between the previous (`alpha` = 0) and current (`alpha` = 1) simulation step
+    pub fn get_interpolated_top_left(&self, alpha: f32) -> cgmath::Point2<f32> {
+        self.prev_top_left + (self.top_left - self.prev_top_left) * alpha
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::physics::RigidBody;
-    use crate::utils::mostly_eq;
+    use crate::physics::{broad_phase_pairs, step_rb_sim, CollisionEventKind, CollisionLayers, ContactTracker, RigidBody};
+    use crate::tiles::test_registry;
+    use crate::utils::{mostly_eq, shared};
+    use crate::world::Tilemap;
+    use std::rc::Rc;
+    use cgmath::Vector2;
+    use ggez::graphics::Rect;
 
     #[test]
     fn test_collision() {
-        let rb_a = RigidBody::new((0.0, 0.0).into(), (1., 1.).into(), Some(1.));
-        let rb_b = RigidBody::new((0.6, 0.5).into(), (1., 1.).into(), Some(1.));
+        let rb_a = RigidBody::new((0.0, 0.0).into(), (1., 1.).into(), Some(1.), CollisionLayers::WORLD, CollisionLayers::ALL);
+        let rb_b = RigidBody::new((0.6, 0.5).into(), (1., 1.).into(), Some(1.), CollisionLayers::WORLD, CollisionLayers::ALL);
 
         let disp =
             RigidBody::get_collision_displacement(&rb_a, &rb_b).expect("no overlap detected");
@@ -216,4 +707,92 @@ mod tests {
         assert!(mostly_eq(disp.x, -0.4, 0.01));
         assert!(mostly_eq(disp.y, -0.5, 0.01));
     }
+
+    #[test]
+    fn test_broad_phase_skips_static_pairs() {
+        let bounds = vec![
+            // two touching static tiles
+            (Rect::new(0.0, 5.0, 1., 1.), false),
+            (Rect::new(1.0, 5.0, 1., 1.), false),
+            // dynamic body above the second tile
+            (Rect::new(1.5, 4.5, 1., 1.), true),
+            // dynamic body far away
+            (Rect::new(10.0, 4.5, 1., 1.), true),
+        ];
+
+        assert_eq!(broad_phase_pairs(&bounds), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_time_of_impact_thin_floor() {
+        // falls 3 units in one step, through a 0.1 thick floor starting 1 unit below
+        let falling = Rect::new(0.0, 0.0, 1., 1.);
+        let floor = Rect::new(-2.0, 2.0, 5., 0.1);
+
+        let contact = RigidBody::get_time_of_impact(&falling, (0.5, 3.0).into(), &floor)
+            .expect("tunnelled through the floor");
+
+        assert!(mostly_eq(contact.time, 1. / 3., 0.001));
+        assert_eq!(contact.normal, Vector2::new(0., -1.));
+    }
+
+    #[test]
+    fn test_resolve_collision_by_weight() {
+        let mut player = RigidBody::new((0.0, 0.0).into(), (1., 1.).into(), Some(1.), CollisionLayers::WORLD, CollisionLayers::ALL);
+        let mut crate_rb = RigidBody::new((0.5, 0.0).into(), (1., 1.).into(), Some(9.), CollisionLayers::WORLD, CollisionLayers::ALL);
+        *player.velocity_mut() = Vector2::new(2., 0.);
+
+        let disp = RigidBody::get_collision_displacement(&player, &crate_rb)
+            .expect("no overlap detected");
+        RigidBody::resolve_collision(&mut player, &mut crate_rb, disp);
+
+        // the crate is nine times heavier, so it only moves a tenth of the way
+        assert!(mostly_eq(player.get_top_left().x, -0.45, 0.001));
+        assert!(mostly_eq(crate_rb.get_top_left().x, 0.55, 0.001));
+
+        // momentum is conserved
+        let momentum = player.velocity.x + 9. * crate_rb.velocity.x;
+        assert!(mostly_eq(momentum, 2., 0.001));
+        assert!(crate_rb.velocity.x > 0.);
+    }
+
+    #[test]
+    fn test_collision_layers() {
+        let player_mask = CollisionLayers::WORLD | CollisionLayers::PICKUP;
+        let player_a = RigidBody::new((0.0, 0.0).into(), (1., 1.).into(), Some(1.), CollisionLayers::PLAYER, player_mask);
+        let player_b = RigidBody::new((0.5, 0.0).into(), (1., 1.).into(), Some(1.), CollisionLayers::PLAYER, player_mask);
+        let pickup = RigidBody::new((0.5, 0.0).into(), (1., 1.).into(), None, CollisionLayers::PICKUP, CollisionLayers::PLAYER);
+        let projectile = RigidBody::new((0.5, 0.0).into(), (1., 1.).into(), Some(1.), CollisionLayers::PROJECTILE, CollisionLayers::ALL);
+
+        assert!(!RigidBody::can_collide(&player_a, &player_b));
+        assert!(RigidBody::can_collide(&player_a, &pickup));
+        assert!(!RigidBody::can_collide(&projectile, &pickup));
+        // the player doesn't react to projectiles, so neither does the projectile to the player
+        assert!(!RigidBody::can_collide(&projectile, &player_a));
+    }
+
+    #[test]
+    fn test_trigger_events() {
+        let body = shared(RigidBody::new((0.0, 0.0).into(), (1., 1.).into(), Some(1.), CollisionLayers::PLAYER, CollisionLayers::ALL));
+        let mut trigger = RigidBody::new((0.0, 2.0).into(), (1., 1.).into(), None, CollisionLayers::PICKUP, CollisionLayers::PLAYER);
+        trigger.set_trigger(true);
+        let trigger = shared(trigger);
+
+        let mut rbs = vec![Rc::downgrade(&body), Rc::downgrade(&trigger)];
+        let tiles = Tilemap::new(test_registry());
+        let mut tracker = ContactTracker::default();
+        let mut frame_drawables = vec![];
+
+        let mut kinds = vec![];
+        for _ in 0..120 {
+            let events = step_rb_sim(&mut rbs, &tiles, &mut tracker, 1. / 60., &mut frame_drawables);
+            kinds.extend(events.into_iter().map(|e| e.kind));
+        }
+
+        // falls right through the trigger
+        assert!(body.borrow().get_top_left().y > 3.);
+        assert_eq!(kinds.first(), Some(&CollisionEventKind::Enter));
+        assert_eq!(kinds.last(), Some(&CollisionEventKind::Exit));
+        assert!(kinds[1..kinds.len() - 1].iter().all(|k| *k == CollisionEventKind::Stay));
+    }
 }
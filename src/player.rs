@@ -1,4 +1,4 @@
-use crate::physics::RigidBody;
+use crate::physics::{CollisionLayers, RigidBody};
 use crate::DebugDrawable;
 use cgmath::Vector2;
 use ggez::graphics::{Color, DrawMode, DrawParam, Mesh, Font, Scale};
@@ -7,39 +7,66 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::borrow::Borrow;
 use crate::game::Game;
+use crate::networking::packets::PlayerInput;
 
 pub struct Player {
     pub rb: Rc<RefCell<RigidBody>>,
     id: usize,
-    sprite: Mesh,
 }
 
+/// Horizontal acceleration while moving, in cells per second squared
+const MOVE_ACCELERATION: f32 = 10.0;
+const JUMP_POWER: f32 = 12.0;
+
 const RED: Color = Color::new(1.0, 0.0, 0.0, 1.0);
 const GREEN: Color = Color::new(0.0, 1.0, 0.0, 1.0);
 const BLUE: Color = Color::new(0.0, 0.0, 1.0, 1.0);
 impl Player {
 
+    /// Jumps if the player stands on something
     pub fn jump(&mut self, power: f32) {
-        (*self.rb).borrow_mut().velocity_mut().y -= power;
+        let mut rb = (*self.rb).borrow_mut();
+        if rb.contacts().on_ground {
+            rb.velocity_mut().y -= power;
+        }
     }
 
-    pub fn create(
-        ctx: &mut Context,
-        start_pos: cgmath::Point2<f32>,
-        id: usize,
-    ) -> GameResult<Player> {
-        let rb = RigidBody::new(start_pos, Vector2::new(1f32, 1f32), Some(1.0));
-        let mesh = Mesh::new_rectangle(
-            ctx,
-            DrawMode::fill(),
-            rb.get_dimensions_rect(),
-            Color::from_rgb(255, 100, 0),
-        )?;
-        Ok(Player {
+    /// Falls through the one way platform the player stands on
+    pub fn drop_through(&mut self) {
+        (*self.rb).borrow_mut().drop_through();
+    }
+
+    /// Applies the input for one tick of `delta` seconds, e.g. one received from a client
+    pub fn apply_input(&mut self, input: &PlayerInput, delta: f32) {
+        // the input may come from the network, so it can't move faster than the keyboard
+        let move_x = if input.move_x.is_finite() { input.move_x.clamp(-1.0, 1.0) } else { 0.0 };
+        (*self.rb).borrow_mut().velocity_mut().x += move_x * delta * MOVE_ACCELERATION;
+
+        if input.jump {
+            self.jump(JUMP_POWER);
+        }
+        if input.drop_through {
+            self.drop_through();
+        }
+    }
+
+    pub fn new(start_pos: cgmath::Point2<f32>, id: usize) -> Player {
+        let rb = RigidBody::new(
+            start_pos,
+            Vector2::new(1f32, 1f32),
+            Some(1.0),
+            CollisionLayers::PLAYER,
+            CollisionLayers::WORLD
+                | CollisionLayers::PLATFORM
+                | CollisionLayers::ENEMY
+                | CollisionLayers::PROJECTILE
+                | CollisionLayers::PICKUP
+                | CollisionLayers::TRIGGER,
+        );
+        Player {
             rb: Rc::new(RefCell::new(rb)),
-            id: 0,
-            sprite: mesh,
-        })
+            id,
+        }
     }
 }
 
@@ -51,7 +78,7 @@ impl DebugDrawable for Player {
         ggez::graphics::draw(
             ctx,
             &debug_text,
-            DrawParam::default().dest(game.cam.world_to_screen(rb.get_top_left())))
+            DrawParam::default().dest(game.cam.world_to_screen(rb.get_interpolated_top_left(game.interpolation_alpha()))))
     }
 
     fn debug_draw_worldspace(&mut self, ctx: &mut Context, game: &Game) -> GameResult<()> {
@@ -60,7 +87,7 @@ impl DebugDrawable for Player {
             Mesh::new_rectangle(ctx, DrawMode::stroke(0.1), rb.get_dimensions_rect(), GREEN)?;
 
         //debug_text.set_font(Font::default(), Scale::uniform(2.));
-        ggez::graphics::draw(ctx, &bbox, DrawParam::default().dest(rb.get_top_left()))
+        ggez::graphics::draw(ctx, &bbox, DrawParam::default().dest(rb.get_interpolated_top_left(game.interpolation_alpha())))
     }
 
 
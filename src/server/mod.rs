@@ -1,48 +1,402 @@
 use async_std::prelude::*;
-use async_std::{net, task};
+use async_std::{io, net, task};
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::Ordering;
-use crate::networking::packets;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use crate::networking::{self, packets::{self, EntityNetworkData, NetError, Packet, PlayerInput}};
+use crate::simulation::Simulation;
+use crate::player::Player;
+use crate::timestep::{self, FixedTimestep};
+use crate::utils::Shared;
+use crate::world::{Tilemap, CHUNK_SIZE};
+use crate::{level, DebugDrawable};
 
+/// Address the server listens on if none is given
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:4321";
 
-pub fn start() {
-    println!("Server starting up...");
+/// Chunks within this many chunks of a player are sent to its client
+const CHUNK_SEND_RADIUS: isize = 2;
+
+/// What the connection tasks report to the thread that owns the simulation
+enum ServerEvent {
+    Connected { client: u64, outgoing: mpsc::Sender<Vec<u8>> },
+    Packet { client: u64, packet: Packet },
+    Disconnected { client: u64 },
+}
 
-    task::block_on(run_server());
+struct Client {
+    /// Encoded packets, the writer thread of the client sends them in order
+    outgoing: mpsc::Sender<Vec<u8>>,
+    /// `None` until the client joined
+    player: Option<Shared<Player>>,
+    name: String,
+    /// Latest input, jumping and dropping through are kept until the next tick used them
+    input: PlayerInput,
+    chunks_sent: HashSet<(isize, isize)>,
+}
+
+impl Client {
+    /// Packets to clients that already disconnected are dropped, their reader reports the disconnect
+    fn send(&self, packet: &Packet) {
+        let _ = self.outgoing.send(packets::encode_packet(packet));
+    }
 }
 
-async fn handle_client(mut stream: net::TcpStream) {
+/// The authoritative game state: applies the inputs of the clients to their players
+/// and sends every client the entities and the chunks around its player.
+///
+/// A client sends `Join` first, the first packet it receives is its own `Join` with the id of its player,
+/// followed by the `Join`s of the players that are already there.
+struct Server {
+    sim: Simulation,
+    clients: HashMap<u64, Client>,
+    frame_drawables: Vec<Box<dyn DebugDrawable>>,
+}
+
+/// Listens on the address, separate from `start` so clients can connect as soon as this returns
+pub fn bind(address: &str) -> io::Result<net::TcpListener> {
+    task::block_on(net::TcpListener::bind(address))
+}
+
+/// Runs the server on the calling thread until `SHOULD_TERMINATE` is set.
+/// The simulation is stepped at `timestep::DEFAULT_TICK_RATE`, the connections are handled by async tasks.
+pub fn start(listener: net::TcpListener, sim: Simulation) {
+    println!("Server starting up...");
+    if let Ok(address) = listener.local_addr() {
+        println!("Server listening on {}", address);
+    }
+
+    let (events_tx, events) = mpsc::channel();
+    task::spawn(accept_clients(listener, events_tx));
+
+    let mut server = Server::new(sim);
+    let mut timestep = FixedTimestep::new(timestep::DEFAULT_TICK_RATE, timestep::DEFAULT_MAX_CATCH_UP_STEPS);
+    let mut last_update = Instant::now();
+
     while !crate::SHOULD_TERMINATE.load(Ordering::Relaxed) {
-        let mut header_buf = [0u8; 8];
-        if let Ok(rres) = async_std::future::timeout(std::time::Duration::from_millis(10), stream.read_exact(&mut header_buf)).await {
-            rres.expect("Failed to receive packet header");
-            println!("got new packet");
-            match packets::decode_next_packet(&header_buf, &mut stream).await {
-                Ok(packet) => {
-                    println!("new packet: {:?}", packet);
-                },
-                Err(e) => eprintln!("Error while decoding packet: {}", e)
+        for event in events.try_iter() {
+            server.handle_event(event);
+        }
+
+        let now = Instant::now();
+        let ticks = timestep.advance((now - last_update).as_secs_f32());
+        last_update = now;
+
+        for _ in 0..ticks {
+            server.tick(timestep.tick_delta());
+        }
+        if ticks > 0 {
+            server.send_updates();
+        }
+
+        // sleep until the next tick is due
+        std::thread::sleep(Duration::from_secs_f32((1.0 - timestep.alpha()) * timestep.tick_delta()));
+    }
+
+    // dropping the clients ends their writer threads, which close the connections
+    drop(server);
+    println!("Server terminated");
+}
+
+impl Server {
+    fn new(sim: Simulation) -> Server {
+        Server {
+            sim,
+            clients: HashMap::new(),
+            frame_drawables: vec![],
+        }
+    }
+
+    fn handle_event(&mut self, event: ServerEvent) {
+        match event {
+            ServerEvent::Connected { client, outgoing } => {
+                self.clients.insert(client, Client {
+                    outgoing,
+                    player: None,
+                    name: String::new(),
+                    input: PlayerInput::default(),
+                    chunks_sent: HashSet::new(),
+                });
+            }
+            ServerEvent::Packet { client, packet } => self.handle_packet(client, packet),
+            ServerEvent::Disconnected { client } => self.remove_client(client),
+        }
+    }
+
+    fn handle_packet(&mut self, client_id: u64, packet: Packet) {
+        let client = match self.clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        match packet {
+            Packet::Join { name, .. } => {
+                if client.player.is_some() {
+                    return;
+                }
+
+                let spawn = self.sim.player_spawn(self.sim.players.len());
+                let player = self.sim.spawn_player(spawn);
+                let player_id = entity_id(&player);
+                client.player = Some(player);
+                client.name = name.clone();
+                println!("{} joined as player {}", name, player_id);
+
+                client.send(&Packet::Join { player_id, name: name.clone() });
+                let client = &self.clients[&client_id];
+                for (&other_id, other) in &self.clients {
+                    if other_id == client_id {
+                        continue;
+                    }
+                    if let Some(other_player) = &other.player {
+                        client.send(&Packet::Join { player_id: entity_id(other_player), name: other.name.clone() });
+                        other.send(&Packet::Join { player_id, name: name.clone() });
+                    }
+                }
+            }
+            Packet::PlayerInput { input, .. } => {
+                // the client can only control its own player, whatever id it sends
+                client.input = PlayerInput {
+                    jump: client.input.jump || input.jump,
+                    drop_through: client.input.drop_through || input.drop_through,
+                    ..input
+                };
+            }
+            Packet::Leave { .. } => self.remove_client(client_id),
+            Packet::Chat { message, .. } => {
+                if let Some(player_id) = client.player.as_ref().map(entity_id) {
+                    self.broadcast(&Packet::Chat { player_id, message });
+                }
+            }
+            Packet::EntitiesFrameData(_) | Packet::ChunkData { .. } => {
+                eprintln!("Client {} sent a packet only the server sends, ignored", client_id);
+            }
+        }
+    }
+
+    /// Forgets the client and removes its player, dropping the client also closes its connection
+    fn remove_client(&mut self, client_id: u64) {
+        let player = match self.clients.remove(&client_id).and_then(|client| client.player) {
+            Some(player) => player,
+            None => return,
+        };
+
+        let player_id = entity_id(&player);
+        println!("Player {} left", player_id);
+        self.sim.remove_player(&player);
+        self.broadcast(&Packet::Leave { player_id });
+    }
+
+    fn tick(&mut self, delta: f32) {
+        for client in self.clients.values_mut() {
+            if let Some(player) = &client.player {
+                player.borrow_mut().apply_input(&client.input, delta);
+                client.input.jump = false;
+                client.input.drop_through = false;
             }
         }
+
+        self.sim.step(delta, &mut self.frame_drawables);
+        self.sim.stream_chunks(&[]);
+        self.frame_drawables.clear();
+    }
+
+    /// Sends the chunks the clients don't have yet and a snapshot of all players
+    fn send_updates(&mut self) {
+        self.send_chunks();
+
+        let entities: Box<[EntityNetworkData]> = self
+            .sim
+            .players
+            .iter()
+            .map(|player| {
+                let rb = player.borrow().rb.clone();
+                let rb = rb.borrow();
+                let (pos, vel) = (rb.get_top_left(), rb.velocity());
+                EntityNetworkData { id: rb.id(), pos: (pos.x, pos.y), vel: (vel.x, vel.y) }
+            })
+            .collect();
+        self.broadcast(&Packet::EntitiesFrameData(entities));
+    }
+
+    fn send_chunks(&mut self) {
+        let mut tiles = self.sim.tiles.borrow_mut();
+        for client in self.clients.values_mut() {
+            let player = match &client.player {
+                Some(player) => player,
+                None => continue,
+            };
+
+            let mut area = player.borrow().rb.borrow().get_transformed_rect();
+            let margin = (CHUNK_SEND_RADIUS * CHUNK_SIZE) as f32;
+            area.translate([-margin, -margin]);
+            area.w += 2.0 * margin;
+            area.h += 2.0 * margin;
+            tiles.request_area(area);
+
+            for coords in Tilemap::chunks_in_area(area) {
+                if client.chunks_sent.contains(&coords) {
+                    continue;
+                }
+                if let Some(layers) = tiles.chunk_layers(coords) {
+                    let chunk = level::encode_chunk(&layers, tiles.registry());
+                    client.send(&Packet::ChunkData { x: coords.0 as i64, y: coords.1 as i64, chunk });
+                    client.chunks_sent.insert(coords);
+                }
+            }
+        }
+    }
+
+    /// Sends the packet to every client that joined
+    fn broadcast(&self, packet: &Packet) {
+        let bytes = packets::encode_packet(packet);
+        for client in self.clients.values().filter(|client| client.player.is_some()) {
+            let _ = client.outgoing.send(bytes.clone());
+        }
     }
 }
 
-async fn run_server() -> async_std::io::Result<()> {
-    let listener: net::TcpListener = net::TcpListener::bind("127.0.0.1:4321").await?;
+/// Id of the body of the player, which is also its id in snapshots
+fn entity_id(player: &Shared<Player>) -> u64 {
+    player.borrow().rb.borrow().id()
+}
+
+async fn accept_clients(listener: net::TcpListener, events: mpsc::Sender<ServerEvent>) {
     let mut incoming = listener.incoming();
+    let mut next_client = 0;
+
     while !crate::SHOULD_TERMINATE.load(Ordering::Relaxed) {
         if let Ok(Some(Ok(stream))) =
             async_std::future::timeout(std::time::Duration::from_millis(10), incoming.next()).await
         {
-            println!("new connection");
+            let client = next_client;
+            next_client += 1;
+            println!("new connection, client {}", client);
 
+            let (outgoing, outgoing_rx) = mpsc::channel();
+            let writer = stream.clone();
+            std::thread::spawn(move || networking::write_packets(writer, outgoing_rx));
 
-            task::spawn(handle_client(stream));
+            if events.send(ServerEvent::Connected { client, outgoing }).is_err() {
+                return;
+            }
+            task::spawn(handle_client(client, stream, events.clone()));
         }
+    }
+}
+
+/// Reads the packets of one client until the connection is closed, which happens at the latest
+/// when the server shuts down and the writer closes it
+async fn handle_client(client: u64, mut stream: net::TcpStream, events: mpsc::Sender<ServerEvent>) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown peer".to_owned());
+
+    loop {
+        let mut header_buf = [0u8; packets::HEADER_SIZE];
+        let res = match stream.read_exact(&mut header_buf).await {
+            Ok(()) => packets::decode_next_packet(&header_buf, &mut stream).await,
+            Err(e) => Err(NetError::Io(e))
+        };
 
-        //println!("server still active");
+        // after a bad packet the stream can't be trusted to be in sync, so the client is dropped
+        match res {
+            Ok(packet) => {
+                if events.send(ServerEvent::Packet { client, packet }).is_err() {
+                    break;
+                }
+            },
+            Err(NetError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                println!("{} disconnected", peer);
+                break;
+            },
+            Err(e @ NetError::Io(_)) => {
+                eprintln!("Dropping {}: {}", peer, e);
+                break;
+            },
+            Err(e @ NetError::VersionMismatch { .. }) => {
+                eprintln!("{} can't join: {}", peer, e);
+                break;
+            },
+            Err(e @ NetError::UnknownType(_))
+            | Err(e @ NetError::BadSignature)
+            | Err(e @ NetError::Oversized(_))
+            | Err(e @ NetError::Truncated)
+            | Err(e @ NetError::Malformed(_)) => {
+                eprintln!("Dropping {} after an invalid packet: {}", peer, e);
+                break;
+            }
+        }
     }
 
-    println!("Server terminated");
+    let _ = events.send(ServerEvent::Disconnected { client });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::networking::packets::{decode_next_packet, Packet, PlayerInput, HEADER_SIZE};
+    use crate::server::{Server, ServerEvent};
+    use crate::simulation::Simulation;
+    use crate::tiles::test_registry;
+    use async_std::task;
+    use std::convert::TryInto;
+    use std::sync::mpsc;
 
-    Ok(())
+    fn received(packets: &mpsc::Receiver<Vec<u8>>) -> Vec<Packet> {
+        packets
+            .try_iter()
+            .map(|bytes| {
+                let header: [u8; HEADER_SIZE] = bytes[..HEADER_SIZE].try_into().unwrap();
+                task::block_on(decode_next_packet(&header, &mut &bytes[HEADER_SIZE..])).unwrap()
+            })
+            .collect()
+    }
+
+    fn connect(server: &mut Server, client: u64, name: &str) -> mpsc::Receiver<Vec<u8>> {
+        let (outgoing, packets) = mpsc::channel();
+        server.handle_event(ServerEvent::Connected { client, outgoing });
+        server.handle_event(ServerEvent::Packet { client, packet: Packet::Join { player_id: 0, name: name.to_owned() } });
+        packets
+    }
+
+    #[test]
+    fn test_server_moves_players_by_their_input() {
+        let mut server = Server::new(Simulation::new(test_registry()));
+        let a = connect(&mut server, 0, "a");
+        let b = connect(&mut server, 1, "b");
+
+        // everyone learns their own id first and then about the others
+        let a_id = match received(&a).as_slice() {
+            [Packet::Join { player_id, .. }, Packet::Join { name, .. }] if name == "b" => *player_id,
+            other => panic!("unexpected packets {:?}", other),
+        };
+        let b_id = match received(&b).as_slice() {
+            [Packet::Join { player_id, .. }, Packet::Join { player_id: other, .. }] if *other == a_id => *player_id,
+            other => panic!("unexpected packets {:?}", other),
+        };
+
+        let input = PlayerInput { tick: 0, move_x: 1.0, jump: false, drop_through: false };
+        // the player id is ignored, clients can only move their own player
+        server.handle_event(ServerEvent::Packet { client: 0, packet: Packet::PlayerInput { player_id: b_id, input } });
+        for _ in 0..10 {
+            server.tick(1.0 / 60.0);
+        }
+        server.send_updates();
+
+        let mut snapshots: Vec<_> = received(&b)
+            .into_iter()
+            .filter_map(|packet| match packet {
+                Packet::EntitiesFrameData(entities) => Some(entities),
+                Packet::ChunkData { .. } => None,
+                other => panic!("unexpected packet {:?}", other),
+            })
+            .collect();
+        let snapshot = snapshots.pop().expect("no snapshot sent");
+        let vel_x = |id| snapshot.iter().find(|entity| entity.id == id).map(|entity| entity.vel.0);
+        assert!(vel_x(a_id).unwrap() > 0.0);
+        assert_eq!(vel_x(b_id), Some(0.0));
+
+        server.handle_event(ServerEvent::Disconnected { client: 0 });
+        assert_eq!(server.sim.players.len(), 1);
+        assert!(received(&b).contains(&Packet::Leave { player_id: a_id }));
+    }
 }
@@ -0,0 +1,238 @@
+use crate::{
+    utils::{Shared, SharedWeak, shared},
+    world::{Tilemap, CHUNK_SIZE},
+    worldgen::NoiseGenerator,
+    tiles::TileRegistry,
+    player::Player,
+    physics::{self, CollisionEvent, CollisionLayers, ContactTracker, RigidBody},
+    tiled::{TiledMap, TriggerRegion},
+    DebugDrawable
+};
+
+use cgmath::Point2;
+use ggez::graphics::Rect;
+
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The game logic without any graphics context.
+/// Owns the tilemap, the players and all rigid bodies and can be stepped with any delta,
+/// so it runs the same inside the ggez window and on a headless machine.
+pub struct Simulation {
+    pub tiles: Shared<Tilemap>,
+    pub players: Vec<Shared<Player>>,
+    rigidbodies: Vec<SharedWeak<RigidBody>>,
+    contact_tracker: ContactTracker,
+    /// Where players enter the level, the n-th player uses the n-th point
+    spawn_points: Vec<Point2<f32>>,
+    /// Trigger regions of the level and their bodies, which only live as long as the simulation
+    triggers: Vec<(TriggerRegion, Shared<RigidBody>)>
+}
+
+impl Simulation {
+    /// Simulation of the built in test level
+    pub fn new(registry: Arc<TileRegistry>) -> Simulation {
+        let stone = registry.id_by_name("stone").expect("tile registry has no stone");
+        let mut tiles = Tilemap::new(registry);
+
+        // generate boxes
+        for y in 8..=10 {
+            for x in 12..20 {
+                tiles.set_cell(x, y, stone);
+            }
+        }
+
+        let mut sim = Simulation::with_tiles(tiles);
+        sim.spawn_points = vec![Point2::new(15.0, 1.0), Point2::new(17.0, 1.0)];
+        sim
+    }
+
+    /// Simulation of an endless world generated from the seed
+    pub fn generated(registry: Arc<TileRegistry>, seed: u64) -> Simulation {
+        let generator = NoiseGenerator::new(seed, &registry);
+        let spawn_points = (0..2)
+            .map(|idx| {
+                let x = idx * 2;
+                Point2::new(x as f32, (generator.surface_y(x) - 2) as f32)
+            })
+            .collect();
+
+        let mut sim = Simulation::with_tiles(Tilemap::with_generator(registry, Box::new(generator)));
+        sim.spawn_points = spawn_points;
+        sim
+    }
+
+    /// Simulation of a loaded level
+    pub fn with_tiles(tiles: Tilemap) -> Simulation {
+        Simulation {
+            tiles: shared(tiles),
+            players: vec![],
+            rigidbodies: vec![],
+            contact_tracker: ContactTracker::default(),
+            spawn_points: vec![],
+            triggers: vec![]
+        }
+    }
+
+    /// Simulation of a level imported from Tiled, with its spawn points and trigger regions
+    pub fn from_tiled(map: TiledMap) -> Simulation {
+        let mut sim = Simulation::with_tiles(map.tiles);
+        sim.spawn_points = map.spawn_points.iter().map(|spawn| spawn.position).collect();
+
+        for region in map.triggers {
+            let rect = region.rect;
+            let mut rb = RigidBody::new(
+                Point2::new(rect.x, rect.y),
+                (rect.w, rect.h).into(),
+                None,
+                CollisionLayers::TRIGGER,
+                CollisionLayers::PLAYER
+            );
+            rb.set_trigger(true);
+
+            let rb = shared(rb);
+            sim.add_rigidbody(&rb);
+            sim.triggers.push((region, rb));
+        }
+
+        sim
+    }
+
+    /// Spawn position of the n-th player, players share the spawn points if there are too few
+    pub fn player_spawn(&self, index: usize) -> Point2<f32> {
+        if self.spawn_points.is_empty() {
+            return Point2::new(0.0, 0.0);
+        }
+        self.spawn_points[index % self.spawn_points.len()]
+    }
+
+    /// The trigger region a body of a collision event belongs to
+    pub fn trigger_region(&self, rb_id: u64) -> Option<&TriggerRegion> {
+        self.triggers
+            .iter()
+            .find(|(_, rb)| rb.borrow().id() == rb_id)
+            .map(|(region, _)| region)
+    }
+
+    pub fn spawn_player(&mut self, pos: Point2<f32>) -> Shared<Player> {
+        let player = shared(Player::new(pos, self.players.len()));
+
+        self.rigidbodies.push(Rc::downgrade(&player.borrow().rb));
+        self.players.push(player.clone());
+
+        player
+    }
+
+    /// Removes the player, its body leaves the physics once the last reference to the player is dropped
+    pub fn remove_player(&mut self, player: &Shared<Player>) {
+        self.players.retain(|other| !Rc::ptr_eq(other, player));
+    }
+
+    /// Adds a body that isn't owned by a player, e.g. a trigger volume.
+    /// The simulation only keeps a weak reference, the body is removed once the caller drops it.
+    pub fn add_rigidbody(&mut self, rb: &Shared<RigidBody>) {
+        self.rigidbodies.push(Rc::downgrade(rb));
+    }
+
+    /// Keeps the chunks around the players and the extra areas (e.g. the viewport) loaded
+    /// and unloads the rest, if the tilemap streams its chunks
+    pub fn stream_chunks(&mut self, extra_areas: &[Rect]) {
+        let mut areas: Vec<Rect> = self
+            .players
+            .iter()
+            .map(|player| player.borrow().rb.borrow().get_transformed_rect())
+            .collect();
+        areas.extend_from_slice(extra_areas);
+
+        self.tiles.borrow_mut().stream(&areas);
+    }
+
+    /// Advances the simulation by `delta` seconds and returns the collision events of this step.
+    /// Debug drawables generated by the physics step are pushed into `frame_drawables`.
+    pub fn step(&mut self, delta: f32, frame_drawables: &mut Vec<Box<dyn DebugDrawable>>) -> Vec<CollisionEvent> {
+        self.rigidbodies.retain(|rb| rb.strong_count() > 0);
+
+        // the players must not fall through chunks that were never generated
+        {
+            let mut tiles = self.tiles.borrow_mut();
+            for player in &self.players {
+                let mut area = player.borrow().rb.borrow().get_transformed_rect();
+                let margin = CHUNK_SIZE as f32;
+                area.translate([-margin, -margin]);
+                area.w += 2.0 * margin;
+                area.h += 2.0 * margin;
+                tiles.request_area(area);
+            }
+        }
+
+        physics::step_rb_sim(
+            &mut self.rigidbodies,
+            &self.tiles.borrow(),
+            &mut self.contact_tracker,
+            delta,
+            frame_drawables
+        )
+    }
+}
+
+/// Runs the simulation without a window until `SHOULD_TERMINATE` is set.
+pub fn run_headless(delta: f32, mut sim: Simulation) {
+    println!("Running headless simulation with dt = {}", delta);
+
+    for idx in 0..2 {
+        sim.spawn_player(sim.player_spawn(idx));
+    }
+
+    // nobody draws them, but the physics step still produces them
+    let mut frame_drawables = vec![];
+
+    while !crate::SHOULD_TERMINATE.load(Ordering::Relaxed) {
+        sim.step(delta, &mut frame_drawables);
+        sim.stream_chunks(&[]);
+        frame_drawables.clear();
+
+        std::thread::sleep(Duration::from_secs_f32(delta));
+    }
+
+    println!("Simulation terminated");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulation::Simulation;
+    use crate::tiles::test_registry;
+    use crate::utils::mostly_eq;
+    use cgmath::Point2;
+
+    #[test]
+    fn test_headless_player_lands_on_boxes() {
+        let mut sim = Simulation::new(test_registry());
+        let player = sim.spawn_player(Point2::new(15.0, 1.0));
+
+        let mut frame_drawables = vec![];
+        for _ in 0..600 {
+            sim.step(1.0 / 60.0, &mut frame_drawables);
+            frame_drawables.clear();
+        }
+
+        let y = player.borrow().rb.borrow().get_top_left().y;
+        assert!(mostly_eq(y, 7.0, 0.05), "player should rest on top of the boxes, is at y = {}", y);
+        assert!(player.borrow().rb.borrow().contacts().on_ground);
+    }
+
+    #[test]
+    fn test_generated_world_has_ground_under_spawn() {
+        let mut sim = Simulation::generated(test_registry(), 7);
+        let player = sim.spawn_player(sim.player_spawn(0));
+
+        let mut frame_drawables = vec![];
+        for _ in 0..300 {
+            sim.step(1.0 / 60.0, &mut frame_drawables);
+            frame_drawables.clear();
+        }
+
+        assert!(player.borrow().rb.borrow().contacts().on_ground, "player fell through the generated terrain");
+    }
+}
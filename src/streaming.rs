@@ -0,0 +1,69 @@
+use crate::level::{self, LevelError};
+use crate::tiles::TileRegistry;
+use crate::world::ChunkLayers;
+
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Chunks within this many chunks of the viewport or a player stay loaded
+pub const DEFAULT_ACTIVE_RADIUS: isize = 2;
+
+/// Tells the stores of one process apart
+static NEXT_STORE: AtomicUsize = AtomicUsize::new(0);
+
+/// Holds modified chunks on disk while they are outside the active area.
+/// Each store uses its own directory in the temp dir, which is removed when the store is dropped,
+/// the level itself is only changed by saving it.
+pub struct ChunkStore {
+    dir: PathBuf,
+    /// Chunks that have a file in `dir`
+    stored: HashSet<(isize, isize)>,
+}
+
+impl ChunkStore {
+    pub fn new() -> io::Result<ChunkStore> {
+        let dir = std::env::temp_dir().join(format!(
+            "platformer-chunks-{}-{}",
+            std::process::id(),
+            NEXT_STORE.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(ChunkStore {
+            dir,
+            stored: HashSet::new(),
+        })
+    }
+
+    pub fn contains(&self, coords: (isize, isize)) -> bool {
+        self.stored.contains(&coords)
+    }
+
+    /// Coords of all chunks on disk
+    pub fn stored(&self) -> impl Iterator<Item = (isize, isize)> + '_ {
+        self.stored.iter().copied()
+    }
+
+    pub fn save(&mut self, coords: (isize, isize), layers: &ChunkLayers, registry: &TileRegistry) -> io::Result<()> {
+        std::fs::write(self.path(coords), level::encode_chunk(layers, registry))?;
+        self.stored.insert(coords);
+        Ok(())
+    }
+
+    pub fn load(&self, coords: (isize, isize), registry: &TileRegistry) -> Result<ChunkLayers, LevelError> {
+        let bytes = std::fs::read(self.path(coords)).map_err(LevelError::Io)?;
+        level::decode_chunk(&bytes, coords, registry)
+    }
+
+    fn path(&self, (cx, cy): (isize, isize)) -> PathBuf {
+        self.dir.join(format!("{}_{}.chunk", cx, cy))
+    }
+}
+
+impl Drop for ChunkStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,816 @@
+use crate::tiles::{TileId, TileRegistry, TileShape};
+use crate::world::{TileLayer, Tilemap};
+
+use cgmath::Point2;
+use ggez::graphics::Rect;
+use serde::Deserialize;
+use xml::reader::{EventReader, XmlEvent};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Tiled stores the flip state in the highest bits of a gid
+const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+const FLIPPED_DIAGONALLY: u32 = 0x2000_0000;
+const ROTATED_HEXAGONAL_120: u32 = 0x1000_0000;
+const FLIP_FLAGS: u32 = FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY | FLIPPED_DIAGONALLY | ROTATED_HEXAGONAL_120;
+
+/// Object type (class in newer Tiled versions) that marks spawn points
+const SPAWN_TYPE: &str = "spawn";
+
+/// A level imported from the Tiled map editor.
+///
+/// Tile ids of a tileset are atlas slots of `tiles.png`, unless the tile has a `tile` property
+/// naming a registry entry (only for tilesets embedded in the map).
+/// Horizontally flipped slopes become the mirrored slope, other flips only work on full tiles.
+///
+/// Tile layers named `background` or `foreground` (in any case) fill those layers of the tilemap,
+/// all others the main layer.
+/// Objects of type `spawn` are spawn points, all other rectangles are trigger regions.
+pub struct TiledMap {
+    /// Later tile layers replace the cells of earlier ones in the same tilemap layer
+    pub tiles: Tilemap,
+    pub spawn_points: Vec<SpawnPoint>,
+    pub triggers: Vec<TriggerRegion>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpawnPoint {
+    pub name: String,
+    /// In cells
+    pub position: Point2<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerRegion {
+    pub name: String,
+    /// The object type, e.g. "checkpoint" or "exit"
+    pub kind: String,
+    /// In cells
+    pub rect: Rect,
+}
+
+impl TiledMap {
+    pub fn spawn_point(&self, name: &str) -> Option<Point2<f32>> {
+        self.spawn_points.iter().find(|s| s.name == name).map(|s| s.position)
+    }
+
+    pub fn triggers_at(&self, point: Point2<f32>) -> impl Iterator<Item = &TriggerRegion> {
+        self.triggers.iter().filter(move |t| t.rect.contains(point))
+    }
+}
+
+#[derive(Debug)]
+pub enum TiledError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Xml(xml::reader::Error),
+    /// The file isn't a .tmx, .tmj or .json file
+    UnknownFileType,
+    /// Something the importer can't handle, e.g. an isometric map or zstd compression
+    Unsupported(String),
+    /// The map is malformed, e.g. a missing attribute
+    Invalid(String),
+    /// A gid that belongs to no tileset or to no tile of the registry
+    UnknownTile { gid: u32, x: isize, y: isize },
+}
+
+impl fmt::Display for TiledError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TiledError::Io(e) => write!(f, "failed to read map: {}", e),
+            TiledError::Json(e) => write!(f, "invalid json map: {}", e),
+            TiledError::Xml(e) => write!(f, "invalid tmx map: {}", e),
+            TiledError::UnknownFileType => write!(f, "maps must be .tmx, .tmj or .json files"),
+            TiledError::Unsupported(what) => write!(f, "unsupported map feature: {}", what),
+            TiledError::Invalid(msg) => write!(f, "invalid map: {}", msg),
+            TiledError::UnknownTile { gid, x, y } => write!(f, "unknown tile {} at ({}, {})", gid, x, y),
+        }
+    }
+}
+
+impl std::error::Error for TiledError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TiledError::Io(e) => Some(e),
+            TiledError::Json(e) => Some(e),
+            TiledError::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Imports a .tmx (xml) or .tmj / .json map
+pub fn import(path: &Path, registry: Arc<TileRegistry>) -> Result<TiledMap, TiledError> {
+    let src = std::fs::read_to_string(path).map_err(TiledError::Io)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("tmx") => from_tmx(&src, registry),
+        Some("tmj") | Some("json") => from_tmj(&src, registry),
+        _ => Err(TiledError::UnknownFileType),
+    }
+}
+
+pub fn from_tmx(src: &str, registry: Arc<TileRegistry>) -> Result<TiledMap, TiledError> {
+    build(parse_tmx(src)?, registry)
+}
+
+pub fn from_tmj(src: &str, registry: Arc<TileRegistry>) -> Result<TiledMap, TiledError> {
+    build(parse_tmj(src)?, registry)
+}
+
+/// What the importer needs of a map, independent of the file format
+struct MapData {
+    tile_width: f32,
+    tile_height: f32,
+    tilesets: Vec<Tileset>,
+    /// Flattened, group layers are resolved
+    layers: Vec<Layer>,
+}
+
+struct Tileset {
+    first_gid: u32,
+    /// Local tile id to registry tile name, from the `tile` property
+    names: HashMap<u32, String>,
+}
+
+enum Layer {
+    Tiles { layer: TileLayer, chunks: Vec<TileChunk> },
+    Objects(Vec<Object>),
+}
+
+/// Rectangle of gids, finite maps have one per layer
+struct TileChunk {
+    x: isize,
+    y: isize,
+    width: usize,
+    height: usize,
+    gids: Vec<u32>,
+}
+
+/// In pixels, the top left corner for all object kinds
+struct Object {
+    name: String,
+    kind: String,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    is_point: bool,
+}
+
+fn build(map: MapData, registry: Arc<TileRegistry>) -> Result<TiledMap, TiledError> {
+    let mut tiles = Tilemap::new(registry.clone());
+    let mut spawn_points = vec![];
+    let mut triggers = vec![];
+
+    for layer in map.layers {
+        match layer {
+            Layer::Tiles { layer, chunks } => {
+                for chunk in chunks {
+                    if chunk.gids.len() != chunk.width * chunk.height {
+                        return Err(TiledError::Invalid(format!(
+                            "tile data at ({}, {}) has {} tiles instead of {}x{}",
+                            chunk.x,
+                            chunk.y,
+                            chunk.gids.len(),
+                            chunk.width,
+                            chunk.height
+                        )));
+                    }
+
+                    for (idx, &gid) in chunk.gids.iter().enumerate().filter(|(_, &gid)| gid != 0) {
+                        let x = chunk.x + (idx % chunk.width) as isize;
+                        let y = chunk.y + (idx / chunk.width) as isize;
+                        let tile = resolve_gid(gid, &map.tilesets, &registry)
+                            .ok_or(TiledError::UnknownTile { gid, x, y })??;
+                        tiles.set_layer_cell(layer, x, y, tile);
+                    }
+                }
+            }
+            Layer::Objects(objects) => {
+                for object in objects {
+                    let position = Point2::new(object.x / map.tile_width, object.y / map.tile_height);
+
+                    if object.kind == SPAWN_TYPE {
+                        spawn_points.push(SpawnPoint {
+                            name: object.name,
+                            position,
+                        });
+                    } else if !object.is_point && object.width > 0.0 && object.height > 0.0 {
+                        triggers.push(TriggerRegion {
+                            name: object.name,
+                            kind: object.kind,
+                            rect: Rect::new(
+                                position.x,
+                                position.y,
+                                object.width / map.tile_width,
+                                object.height / map.tile_height,
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(TiledMap {
+        tiles,
+        spawn_points,
+        triggers,
+    })
+}
+
+/// Tilemap layer of a Tiled tile layer
+fn tile_layer(name: &str) -> TileLayer {
+    TileLayer::by_name(&name.to_lowercase()).unwrap_or(TileLayer::Main)
+}
+
+/// Registry tile of a gid, `None` if it's unknown
+fn resolve_gid(gid: u32, tilesets: &[Tileset], registry: &TileRegistry) -> Option<Result<TileId, TiledError>> {
+    let flags = gid & FLIP_FLAGS;
+    let gid = gid & !FLIP_FLAGS;
+
+    let tileset = tilesets
+        .iter()
+        .filter(|t| t.first_gid <= gid)
+        .max_by_key(|t| t.first_gid)?;
+    let local = gid - tileset.first_gid;
+
+    let id = match tileset.names.get(&local) {
+        Some(name) => registry.id_by_name(name)?,
+        None => registry.by_atlas_slot(local as usize)?,
+    };
+
+    let id = if flags & FLIPPED_HORIZONTALLY != 0 {
+        registry.mirrored(id)?
+    } else {
+        id
+    };
+    if flags & !FLIPPED_HORIZONTALLY != 0 && registry.get(id).shape != TileShape::Full {
+        let name = &registry.get(id).name;
+        return Some(Err(TiledError::Unsupported(format!("rotated or vertically flipped '{}'", name))));
+    }
+
+    Some(Ok(id))
+}
+
+/// Decodes the gids of a `<data>` element or json `data` string
+fn decode_gids(data: &str, encoding: Option<&str>, compression: Option<&str>) -> Result<Vec<u32>, TiledError> {
+    match encoding {
+        Some("csv") => data
+            .split(',')
+            .map(|gid| gid.trim().parse::<u32>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| TiledError::Invalid(format!("bad csv tile data: {}", e))),
+        Some("base64") => {
+            let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+            let bytes = base64::decode(&data).map_err(|e| TiledError::Invalid(format!("bad base64 tile data: {}", e)))?;
+
+            let bytes = match compression {
+                None | Some("") => bytes,
+                Some("zlib") => miniz_oxide::inflate::decompress_to_vec_zlib(&bytes)
+                    .map_err(|e| TiledError::Invalid(format!("bad zlib tile data: {:?}", e)))?,
+                Some("gzip") => miniz_oxide::inflate::decompress_to_vec(gzip_body(&bytes)?)
+                    .map_err(|e| TiledError::Invalid(format!("bad gzip tile data: {:?}", e)))?,
+                Some(other) => return Err(TiledError::Unsupported(format!("{} compression", other))),
+            };
+
+            if bytes.len() % 4 != 0 {
+                return Err(TiledError::Invalid("tile data isn't a multiple of 4 bytes".to_owned()));
+            }
+            Ok(bytes
+                .chunks_exact(4)
+                .map(|gid| u32::from_le_bytes([gid[0], gid[1], gid[2], gid[3]]))
+                .collect())
+        }
+        Some(other) => Err(TiledError::Unsupported(format!("{} encoding", other))),
+        None => Err(TiledError::Invalid("tile data without encoding".to_owned())),
+    }
+}
+
+/// The deflate stream of a gzip file, skipping the header
+fn gzip_body(bytes: &[u8]) -> Result<&[u8], TiledError> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+
+    let truncated = || TiledError::Invalid("truncated gzip tile data".to_owned());
+    if bytes.len() < 18 || bytes[0..3] != [0x1f, 0x8b, 8] {
+        return Err(TiledError::Invalid("bad gzip header".to_owned()));
+    }
+
+    let flags = bytes[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = *bytes.get(pos).ok_or_else(truncated)? as usize | (*bytes.get(pos + 1).ok_or_else(truncated)? as usize) << 8;
+        pos += 2 + len;
+    }
+    for &flag in [FNAME, FCOMMENT].iter() {
+        if flags & flag != 0 {
+            pos += bytes.get(pos..).ok_or_else(truncated)?.iter().position(|&b| b == 0).ok_or_else(truncated)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    // the trailer holds crc32 and size
+    bytes.get(pos..bytes.len() - 8).ok_or_else(truncated)
+}
+
+fn check_orientation(orientation: &str) -> Result<(), TiledError> {
+    if orientation == "orthogonal" {
+        Ok(())
+    } else {
+        Err(TiledError::Unsupported(format!("{} orientation", orientation)))
+    }
+}
+
+// ------------------------------------------------------------------ tmj
+
+#[derive(Deserialize)]
+struct JsonMap {
+    orientation: String,
+    tilewidth: f32,
+    tileheight: f32,
+    #[serde(default)]
+    tilesets: Vec<JsonTileset>,
+    layers: Vec<JsonLayer>,
+}
+
+#[derive(Deserialize)]
+struct JsonTileset {
+    firstgid: u32,
+    #[serde(default)]
+    tiles: Vec<JsonTile>,
+}
+
+#[derive(Deserialize)]
+struct JsonTile {
+    id: u32,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+#[derive(Deserialize)]
+struct JsonProperty {
+    name: String,
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonLayer {
+    TileLayer {
+        #[serde(default)]
+        name: String,
+        #[serde(default)]
+        x: isize,
+        #[serde(default)]
+        y: isize,
+        #[serde(default)]
+        width: usize,
+        #[serde(default)]
+        height: usize,
+        data: Option<JsonData>,
+        /// Instead of `data` in infinite maps
+        #[serde(default)]
+        chunks: Vec<JsonChunk>,
+        encoding: Option<String>,
+        compression: Option<String>,
+    },
+    ObjectGroup {
+        objects: Vec<JsonObject>,
+    },
+    Group {
+        layers: Vec<JsonLayer>,
+    },
+    /// Image layers
+    #[serde(other)]
+    Other,
+}
+
+/// Plain gids with csv encoding, base64 otherwise
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonData {
+    Gids(Vec<u32>),
+    Encoded(String),
+}
+
+#[derive(Deserialize)]
+struct JsonChunk {
+    x: isize,
+    y: isize,
+    width: usize,
+    height: usize,
+    data: JsonData,
+}
+
+#[derive(Deserialize)]
+struct JsonObject {
+    #[serde(default)]
+    name: String,
+    #[serde(default, rename = "type", alias = "class")]
+    kind: String,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    #[serde(default)]
+    point: bool,
+    /// Tile objects are positioned by their bottom left corner
+    gid: Option<u32>,
+}
+
+impl JsonData {
+    fn into_gids(self, encoding: Option<&str>, compression: Option<&str>) -> Result<Vec<u32>, TiledError> {
+        match self {
+            JsonData::Gids(gids) => Ok(gids),
+            JsonData::Encoded(data) => decode_gids(&data, encoding, compression),
+        }
+    }
+}
+
+fn parse_tmj(src: &str) -> Result<MapData, TiledError> {
+    let map: JsonMap = serde_json::from_str(src).map_err(TiledError::Json)?;
+    check_orientation(&map.orientation)?;
+
+    let tilesets = map
+        .tilesets
+        .into_iter()
+        .map(|tileset| Tileset {
+            first_gid: tileset.firstgid,
+            names: tileset
+                .tiles
+                .into_iter()
+                .filter_map(|tile| {
+                    let name = tile.properties.into_iter().find(|p| p.name == "tile")?.value;
+                    Some((tile.id, name.as_str()?.to_owned()))
+                })
+                .collect(),
+        })
+        .collect();
+
+    let mut layers = vec![];
+    json_layers(map.layers, &mut layers)?;
+
+    Ok(MapData {
+        tile_width: map.tilewidth,
+        tile_height: map.tileheight,
+        tilesets,
+        layers,
+    })
+}
+
+fn json_layers(json: Vec<JsonLayer>, layers: &mut Vec<Layer>) -> Result<(), TiledError> {
+    for layer in json {
+        match layer {
+            JsonLayer::TileLayer {
+                name,
+                x,
+                y,
+                width,
+                height,
+                data,
+                chunks,
+                encoding,
+                compression,
+            } => {
+                let (encoding, compression) = (encoding.as_deref(), compression.as_deref());
+
+                let mut tile_chunks = vec![];
+                if let Some(data) = data {
+                    tile_chunks.push(TileChunk {
+                        x,
+                        y,
+                        width,
+                        height,
+                        gids: data.into_gids(encoding, compression)?,
+                    });
+                }
+                for chunk in chunks {
+                    tile_chunks.push(TileChunk {
+                        x: chunk.x,
+                        y: chunk.y,
+                        width: chunk.width,
+                        height: chunk.height,
+                        gids: chunk.data.into_gids(encoding, compression)?,
+                    });
+                }
+                layers.push(Layer::Tiles {
+                    layer: tile_layer(&name),
+                    chunks: tile_chunks,
+                });
+            }
+            JsonLayer::ObjectGroup { objects } => layers.push(Layer::Objects(
+                objects
+                    .into_iter()
+                    .map(|o| Object {
+                        y: if o.gid.is_some() { o.y - o.height } else { o.y },
+                        name: o.name,
+                        kind: o.kind,
+                        x: o.x,
+                        width: o.width,
+                        height: o.height,
+                        is_point: o.point,
+                    })
+                    .collect(),
+            )),
+            JsonLayer::Group { layers: group } => json_layers(group, layers)?,
+            JsonLayer::Other => {}
+        }
+    }
+    Ok(())
+}
+
+// ------------------------------------------------------------------ tmx
+
+struct XmlElement {
+    name: String,
+    attributes: HashMap<String, String>,
+    children: Vec<XmlElement>,
+    text: String,
+}
+
+impl XmlElement {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+
+    fn parse_attr<T: FromStr>(&self, name: &str) -> Result<T, TiledError> {
+        let value = self
+            .attr(name)
+            .ok_or_else(|| TiledError::Invalid(format!("<{}> has no {} attribute", self.name, name)))?;
+        value
+            .parse()
+            .map_err(|_| TiledError::Invalid(format!("bad {} attribute '{}' of <{}>", name, value, self.name)))
+    }
+
+    fn parse_attr_or<T: FromStr>(&self, name: &str, default: T) -> Result<T, TiledError> {
+        match self.attr(name) {
+            Some(_) => self.parse_attr(name),
+            None => Ok(default),
+        }
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlElement> {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+}
+
+fn parse_xml(src: &str) -> Result<XmlElement, TiledError> {
+    let mut stack: Vec<XmlElement> = vec![];
+
+    for event in EventReader::from_str(src) {
+        match event.map_err(TiledError::Xml)? {
+            XmlEvent::StartElement { name, attributes, .. } => stack.push(XmlElement {
+                name: name.local_name,
+                attributes: attributes.into_iter().map(|a| (a.name.local_name, a.value)).collect(),
+                children: vec![],
+                text: String::new(),
+            }),
+            XmlEvent::EndElement { .. } => {
+                // the reader already rejects unbalanced tags
+                let element = stack.pop().expect("end tag without start tag");
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text += &text;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Err(TiledError::Invalid("empty document".to_owned()))
+}
+
+fn parse_tmx(src: &str) -> Result<MapData, TiledError> {
+    let map = parse_xml(src)?;
+    if map.name != "map" {
+        return Err(TiledError::Invalid(format!("root element is <{}> instead of <map>", map.name)));
+    }
+    check_orientation(map.attr("orientation").unwrap_or("orthogonal"))?;
+
+    let mut tilesets = vec![];
+    for tileset in map.children_named("tileset") {
+        let mut names = HashMap::new();
+        for tile in tileset.children_named("tile") {
+            let name = tile
+                .children_named("properties")
+                .flat_map(|p| p.children_named("property"))
+                .find(|p| p.attr("name") == Some("tile"))
+                .and_then(|p| p.attr("value"));
+            if let Some(name) = name {
+                names.insert(tile.parse_attr("id")?, name.to_owned());
+            }
+        }
+
+        tilesets.push(Tileset {
+            first_gid: tileset.parse_attr("firstgid")?,
+            names,
+        });
+    }
+
+    let mut layers = vec![];
+    tmx_layers(&map, &mut layers)?;
+
+    Ok(MapData {
+        tile_width: map.parse_attr("tilewidth")?,
+        tile_height: map.parse_attr("tileheight")?,
+        tilesets,
+        layers,
+    })
+}
+
+fn tmx_layers(parent: &XmlElement, layers: &mut Vec<Layer>) -> Result<(), TiledError> {
+    for element in parent.children.iter() {
+        match element.name.as_str() {
+            "layer" => {
+                let data = element
+                    .children_named("data")
+                    .next()
+                    .ok_or_else(|| TiledError::Invalid("<layer> without <data>".to_owned()))?;
+
+                let mut chunks = vec![];
+                if data.children_named("chunk").next().is_some() {
+                    for chunk in data.children_named("chunk") {
+                        chunks.push(tmx_chunk(chunk, data)?);
+                    }
+                } else {
+                    chunks.push(TileChunk {
+                        x: element.parse_attr_or("x", 0)?,
+                        y: element.parse_attr_or("y", 0)?,
+                        width: element.parse_attr("width")?,
+                        height: element.parse_attr("height")?,
+                        gids: tmx_gids(data, data)?,
+                    });
+                }
+                layers.push(Layer::Tiles {
+                    layer: tile_layer(element.attr("name").unwrap_or_default()),
+                    chunks,
+                });
+            }
+            "objectgroup" => {
+                let mut objects = vec![];
+                for object in element.children_named("object") {
+                    let height = object.parse_attr_or("height", 0.0)?;
+                    let y: f32 = object.parse_attr("y")?;
+
+                    objects.push(Object {
+                        name: object.attr("name").unwrap_or_default().to_owned(),
+                        kind: object.attr("type").or_else(|| object.attr("class")).unwrap_or_default().to_owned(),
+                        x: object.parse_attr("x")?,
+                        y: if object.attr("gid").is_some() { y - height } else { y },
+                        width: object.parse_attr_or("width", 0.0)?,
+                        height,
+                        is_point: object.children_named("point").next().is_some(),
+                    });
+                }
+                layers.push(Layer::Objects(objects));
+            }
+            "group" => tmx_layers(element, layers)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn tmx_chunk(chunk: &XmlElement, data: &XmlElement) -> Result<TileChunk, TiledError> {
+    Ok(TileChunk {
+        x: chunk.parse_attr("x")?,
+        y: chunk.parse_attr("y")?,
+        width: chunk.parse_attr("width")?,
+        height: chunk.parse_attr("height")?,
+        gids: tmx_gids(chunk, data)?,
+    })
+}
+
+/// Gids of a `<data>` or `<chunk>` element, the encoding is always set on `<data>`
+fn tmx_gids(element: &XmlElement, data: &XmlElement) -> Result<Vec<u32>, TiledError> {
+    match data.attr("encoding") {
+        // the old format with one element per tile
+        None => element.children_named("tile").map(|tile| tile.parse_attr_or("gid", 0)).collect(),
+        encoding => decode_gids(element.text.trim(), encoding, data.attr("compression")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tiled::{from_tmj, from_tmx, TiledError, FLIPPED_HORIZONTALLY, FLIPPED_VERTICALLY};
+    use crate::tiles::test_registry;
+    use crate::world::TileLayer;
+    use cgmath::Point2;
+
+    // 2 tilesets: tiles.png (atlas slots as local ids) and one whose only tile is named
+    const TMX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.10" orientation="orthogonal" renderorder="right-down" width="4" height="3" tilewidth="32" tileheight="32" infinite="0">
+ <tileset firstgid="1" name="tiles" tilewidth="32" tileheight="32" tilecount="64" columns="8">
+  <image source="tiles.png" width="256" height="256"/>
+ </tileset>
+ <tileset firstgid="65" name="props" tilewidth="32" tileheight="32" tilecount="1" columns="1">
+  <tile id="0">
+   <properties>
+    <property name="tile" value="platform"/>
+   </properties>
+  </tile>
+ </tileset>
+ <group name="level">
+  <layer id="1" name="main" width="4" height="3">
+   <data encoding="csv">
+0,0,0,65,
+0,0,0,0,
+17,17,4,0
+   </data>
+  </layer>
+  <layer id="3" name="Background" width="4" height="3">
+   <data encoding="csv">
+0,0,0,0,
+0,11,0,0,
+0,0,0,0
+   </data>
+  </layer>
+ </group>
+ <objectgroup id="2" name="objects">
+  <object id="1" name="player" type="spawn" x="48" y="16">
+   <point/>
+  </object>
+  <object id="2" name="goal" class="exit" x="64" y="0" width="64" height="32"/>
+ </objectgroup>
+</map>
+"#;
+
+    #[test]
+    fn test_import_tmx() {
+        let map = from_tmx(TMX, test_registry()).expect("failed to import");
+        let registry = map.tiles.registry();
+
+        assert_eq!(map.tiles.get_cell(0, 2), registry.id_by_name("stone").unwrap());
+        assert_eq!(map.tiles.get_cell(2, 2), registry.id_by_name("stone_slope_right").unwrap());
+        assert_eq!(map.tiles.get_cell(3, 0), registry.id_by_name("platform").unwrap());
+        assert!(map.tiles.get_cell(3, 2).is_empty());
+        assert!(map.tiles.get_cell(1, 1).is_empty());
+        assert_eq!(map.tiles.get_layer_cell(TileLayer::Background, 1, 1), registry.id_by_name("stone_wall").unwrap());
+
+        assert_eq!(map.spawn_point("player"), Some(Point2::new(1.5, 0.5)));
+        let triggers: Vec<_> = map.triggers_at(Point2::new(2.5, 0.5)).collect();
+        assert_eq!(triggers.len(), 1);
+        assert_eq!(triggers[0].kind, "exit");
+    }
+
+    #[test]
+    fn test_import_infinite_tmj() {
+        // base64 of the gids [17, 4 | flipped horizontally, 0, 17]
+        let mut bytes = vec![];
+        for &gid in [17, 4 | FLIPPED_HORIZONTALLY, 0, 17].iter() {
+            bytes.extend_from_slice(&u32::to_le_bytes(gid));
+        }
+        let json = format!(
+            r#"{{
+                "orientation": "orthogonal", "infinite": true, "tilewidth": 16, "tileheight": 16,
+                "tilesets": [{{ "firstgid": 1, "source": "tiles.tsj" }}],
+                "layers": [
+                    {{ "type": "tilelayer", "name": "main", "encoding": "base64", "chunks": [
+                        {{ "x": -16, "y": -16, "width": 2, "height": 2, "data": "{}" }},
+                        {{ "x": 16, "y": 0, "width": 2, "height": 1, "data": [17, 0] }}
+                    ] }},
+                    {{ "type": "objectgroup", "name": "objects", "objects": [
+                        {{ "name": "checkpoint 1", "type": "checkpoint", "x": 0, "y": 0, "width": 16, "height": 32 }}
+                    ] }},
+                    {{ "type": "imagelayer", "name": "sky", "image": "sky.png" }}
+                ]
+            }}"#,
+            base64::encode(&bytes)
+        );
+
+        let map = from_tmj(&json, test_registry()).expect("failed to import");
+        let registry = map.tiles.registry();
+
+        assert_eq!(map.tiles.get_cell(-16, -16), registry.id_by_name("stone").unwrap());
+        // the mirrored slope
+        assert_eq!(map.tiles.get_cell(-15, -16), registry.id_by_name("stone_slope_left").unwrap());
+        assert_eq!(map.tiles.get_cell(-15, -15), registry.id_by_name("stone").unwrap());
+        assert_eq!(map.tiles.get_cell(16, 0), registry.id_by_name("stone").unwrap());
+        assert_eq!(map.triggers.len(), 1);
+        assert_eq!(map.triggers[0].rect.h, 2.0);
+
+        // slopes can't be upside down
+        let flipped = json.replace("[17, 0]", &format!("[{}, 0]", 4 | FLIPPED_VERTICALLY));
+        assert!(matches!(from_tmj(&flipped, test_registry()), Err(TiledError::Unsupported(_))));
+
+        let unknown = json.replace("[17, 0]", "[64, 0]");
+        assert!(matches!(from_tmj(&unknown, test_registry()), Err(TiledError::UnknownTile { gid: 64, .. })));
+    }
+}
@@ -0,0 +1,472 @@
+use crate::physics::{Axis, CollisionLayers};
+
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// Compact id of a tile type, this is what chunks store per cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+pub struct TileId(pub u16);
+
+impl TileId {
+    /// Always registered and never drawn, cells of new chunks are empty
+    pub const EMPTY: TileId = TileId(0);
+
+    pub fn is_empty(self) -> bool {
+        self == TileId::EMPTY
+    }
+}
+
+/// How a tile interacts with rigid bodies
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Solidity {
+    /// Bodies pass through, e.g. decoration
+    None,
+    Solid,
+    /// Bodies can jump through it from below and land on top
+    OneWay,
+}
+
+/// Collision shape of a tile inside its cell
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileShape {
+    #[default]
+    Full,
+    /// Lower half of the cell
+    Half,
+    /// 45° ramp rising to the right
+    SlopeRight,
+    /// 45° ramp rising to the left
+    SlopeLeft,
+    /// Lower half of a two cell wide 22.5° ramp rising to the right
+    SlopeRightLow,
+    /// Upper half of a two cell wide 22.5° ramp rising to the right
+    SlopeRightHigh,
+    /// Upper half of a two cell wide 22.5° ramp rising to the left
+    SlopeLeftHigh,
+    /// Lower half of a two cell wide 22.5° ramp rising to the left
+    SlopeLeftLow,
+}
+
+/// How much the 22.5° ramps rise per cell, tan 22.5° = √2 - 1.
+/// The two cells together end below the full cell height, bodies step up the rest.
+const GENTLE_RISE: f32 = 0.414_213_56;
+
+impl TileShape {
+    /// Height of the solid part at the left and right edge of the cell,
+    /// measured from its bottom in cell units.
+    /// Every cell is solid from its bottom up to the line between those two points.
+    pub fn edge_heights(self) -> (f32, f32) {
+        match self {
+            TileShape::Full => (1.0, 1.0),
+            TileShape::Half => (0.5, 0.5),
+            TileShape::SlopeRight => (0.0, 1.0),
+            TileShape::SlopeLeft => (1.0, 0.0),
+            TileShape::SlopeRightLow => (0.0, GENTLE_RISE),
+            TileShape::SlopeRightHigh => (GENTLE_RISE, 2.0 * GENTLE_RISE),
+            TileShape::SlopeLeftHigh => (2.0 * GENTLE_RISE, GENTLE_RISE),
+            TileShape::SlopeLeftLow => (GENTLE_RISE, 0.0),
+        }
+    }
+
+    /// The shape flipped horizontally
+    pub fn mirrored(self) -> TileShape {
+        match self {
+            TileShape::Full => TileShape::Full,
+            TileShape::Half => TileShape::Half,
+            TileShape::SlopeRight => TileShape::SlopeLeft,
+            TileShape::SlopeLeft => TileShape::SlopeRight,
+            TileShape::SlopeRightLow => TileShape::SlopeLeftLow,
+            TileShape::SlopeRightHigh => TileShape::SlopeLeftHigh,
+            TileShape::SlopeLeftHigh => TileShape::SlopeRightHigh,
+            TileShape::SlopeLeftLow => TileShape::SlopeRightLow,
+        }
+    }
+}
+
+/// Rule set choosing a tile's atlas variant from its neighbours.
+/// Neighbours are passed as a bitmask, see `Neighbours`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Autotile {
+    /// 16 variants, one per combination of the four direct neighbours.
+    /// Bit 0 of the variant is set for a neighbour above, 1 right, 2 below and 3 left.
+    Edges,
+    /// 47 variants, diagonal neighbours only count if both adjacent direct neighbours are set
+    Blob,
+}
+
+/// Bits of the neighbour mask, clockwise starting at the top
+pub struct Neighbours;
+
+impl Neighbours {
+    pub const UP: u8 = 1 << 0;
+    pub const UP_RIGHT: u8 = 1 << 1;
+    pub const RIGHT: u8 = 1 << 2;
+    pub const DOWN_RIGHT: u8 = 1 << 3;
+    pub const DOWN: u8 = 1 << 4;
+    pub const DOWN_LEFT: u8 = 1 << 5;
+    pub const LEFT: u8 = 1 << 6;
+    pub const UP_LEFT: u8 = 1 << 7;
+
+    /// Cell offsets of the bits, y pointing down like the world coords
+    pub const OFFSETS: [(isize, isize); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+}
+
+impl Autotile {
+    /// Number of atlas slots the variants take
+    pub fn variant_count(self) -> usize {
+        match self {
+            Autotile::Edges => 16,
+            Autotile::Blob => 47,
+        }
+    }
+
+    /// Variant (offset from the tile's atlas index) for the neighbour mask
+    pub fn variant(self, neighbours: u8) -> usize {
+        match self {
+            Autotile::Edges => {
+                let bit = |n: u8, shift: usize| ((neighbours & n != 0) as usize) << shift;
+                bit(Neighbours::UP, 0) | bit(Neighbours::RIGHT, 1) | bit(Neighbours::DOWN, 2) | bit(Neighbours::LEFT, 3)
+            }
+            Autotile::Blob => {
+                // the variants are the reduced masks in ascending order
+                let mask = Autotile::reduce_corners(neighbours);
+                (0..mask).filter(|&m| Autotile::reduce_corners(m) == m).count()
+            }
+        }
+    }
+
+    /// Clears the diagonal bits that don't affect the look, because a direct neighbour next to them is missing
+    fn reduce_corners(neighbours: u8) -> u8 {
+        let corners = [
+            (Neighbours::UP_RIGHT, Neighbours::UP | Neighbours::RIGHT),
+            (Neighbours::DOWN_RIGHT, Neighbours::DOWN | Neighbours::RIGHT),
+            (Neighbours::DOWN_LEFT, Neighbours::DOWN | Neighbours::LEFT),
+            (Neighbours::UP_LEFT, Neighbours::UP | Neighbours::LEFT),
+        ];
+
+        corners.iter().fold(neighbours, |mask, &(corner, sides)| {
+            if mask & sides == sides {
+                mask
+            } else {
+                mask & !corner
+            }
+        })
+    }
+}
+
+/// Everything the game knows about one kind of tile, one `[[tile]]` entry of the registry file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TileDef {
+    pub id: TileId,
+    pub name: String,
+    /// Slot of the tile in the texture atlas, counted row by row
+    pub atlas_index: usize,
+    #[serde(default)]
+    pub autotile: Option<Autotile>,
+    pub solidity: Solidity,
+    #[serde(default)]
+    pub shape: TileShape,
+    #[serde(default)]
+    pub friction: f32,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl TileDef {
+    fn empty() -> TileDef {
+        TileDef {
+            id: TileId::EMPTY,
+            name: "empty".to_owned(),
+            atlas_index: 0,
+            autotile: None,
+            solidity: Solidity::None,
+            shape: TileShape::Full,
+            friction: 0.0,
+            tags: vec![],
+        }
+    }
+
+    /// Solid tiles (including one way platforms) block rigid bodies
+    pub fn is_solid(&self) -> bool {
+        self.solidity != Solidity::None
+    }
+
+    /// Atlas slot to draw the tile with, given the mask of solid neighbours
+    pub fn atlas_index_for(&self, neighbours: u8) -> usize {
+        self.atlas_index + self.autotile.map_or(0, |autotile| autotile.variant(neighbours))
+    }
+
+    /// Solid neighbours this tile's autotiling connects to
+    pub fn connects_to(&self, other: &TileDef) -> bool {
+        other.solidity == Solidity::Solid
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// If the tile stops a body moving `distance` along `axis`.
+    /// One way platforms only stop bodies falling onto them.
+    pub fn blocks(&self, axis: Axis, distance: f32) -> bool {
+        match self.solidity {
+            Solidity::None => false,
+            Solidity::OneWay => match axis {
+                Axis::Y => distance > 0.0,
+                Axis::X => false,
+            },
+            Solidity::Solid => true,
+        }
+    }
+
+    /// Height of the solid part at `x` (0 = left edge, 1 = right edge of the cell)
+    pub fn surface_height(&self, x: f32) -> f32 {
+        if !self.is_solid() {
+            return 0.0;
+        }
+        let (left, right) = self.shape.edge_heights();
+        left + (right - left) * x
+    }
+
+    /// Layer the tile collides on, bodies whose mask doesn't contain it pass through
+    pub fn collision_layer(&self) -> CollisionLayers {
+        match self.solidity {
+            Solidity::None => CollisionLayers::NONE,
+            Solidity::OneWay => CollisionLayers::PLATFORM,
+            Solidity::Solid => CollisionLayers::WORLD,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    /// The file parsed, but describes an unusable registry
+    Invalid(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Io(e) => write!(f, "failed to read tile registry: {}", e),
+            RegistryError::Parse(e) => write!(f, "failed to parse tile registry: {}", e),
+            RegistryError::Invalid(msg) => write!(f, "invalid tile registry: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistryError::Io(e) => Some(e),
+            RegistryError::Parse(e) => Some(e),
+            RegistryError::Invalid(_) => None,
+        }
+    }
+}
+
+/// Layout of the registry file
+#[derive(Deserialize)]
+struct RegistryFile {
+    /// Tiles per row and column of the texture atlas
+    atlas_size: usize,
+    #[serde(rename = "tile", default)]
+    tiles: Vec<TileDef>,
+}
+
+/// All tile types of the game, loaded from `resources/tiles.toml`.
+/// Id 0 is reserved for the empty tile, ids that aren't listed behave like it.
+#[derive(Debug)]
+pub struct TileRegistry {
+    atlas_size: usize,
+    /// Indexed by the tile id
+    tiles: Vec<TileDef>,
+    by_name: HashMap<String, TileId>,
+}
+
+impl TileRegistry {
+    pub fn load(path: &Path) -> Result<TileRegistry, RegistryError> {
+        let src = std::fs::read_to_string(path).map_err(RegistryError::Io)?;
+        TileRegistry::from_toml(&src)
+    }
+
+    pub fn from_toml(src: &str) -> Result<TileRegistry, RegistryError> {
+        let file: RegistryFile = toml::from_str(src).map_err(RegistryError::Parse)?;
+
+        if file.atlas_size == 0 {
+            return Err(RegistryError::Invalid("atlas_size must be positive".to_owned()));
+        }
+
+        let max_id = file.tiles.iter().map(|t| t.id.0).max().unwrap_or(0);
+        let mut tiles: Vec<Option<TileDef>> = vec![None; max_id as usize + 1];
+        let mut by_name = HashMap::new();
+        by_name.insert("empty".to_owned(), TileId::EMPTY);
+
+        for tile in file.tiles {
+            if tile.id.is_empty() {
+                return Err(RegistryError::Invalid(format!("tile '{}' uses the reserved id 0", tile.name)));
+            }
+            let variants = tile.autotile.map_or(1, Autotile::variant_count);
+            if tile.atlas_index + variants > file.atlas_size * file.atlas_size {
+                return Err(RegistryError::Invalid(format!(
+                    "atlas slots {}..{} of tile '{}' are outside the {}x{} atlas",
+                    tile.atlas_index,
+                    tile.atlas_index + variants,
+                    tile.name,
+                    file.atlas_size,
+                    file.atlas_size
+                )));
+            }
+            if by_name.insert(tile.name.clone(), tile.id).is_some() {
+                return Err(RegistryError::Invalid(format!("tile name '{}' is used twice", tile.name)));
+            }
+
+            let slot = &mut tiles[tile.id.0 as usize];
+            if slot.is_some() {
+                return Err(RegistryError::Invalid(format!("tile id {} is used twice", tile.id.0)));
+            }
+            *slot = Some(tile);
+        }
+
+        Ok(TileRegistry {
+            atlas_size: file.atlas_size,
+            tiles: tiles.into_iter().map(|t| t.unwrap_or_else(TileDef::empty)).collect(),
+            by_name,
+        })
+    }
+
+    /// Tiles per row and column of the texture atlas
+    pub fn atlas_size(&self) -> usize {
+        self.atlas_size
+    }
+
+    /// Definition of the tile, unknown ids are empty
+    pub fn get(&self, id: TileId) -> &TileDef {
+        self.tiles.get(id.0 as usize).unwrap_or(&self.tiles[0])
+    }
+
+    pub fn id_by_name(&self, name: &str) -> Option<TileId> {
+        self.by_name.get(name).copied()
+    }
+
+    /// All registered tiles except the empty one, ordered by id
+    pub fn tiles(&self) -> impl Iterator<Item = &TileDef> {
+        self.tiles.iter().filter(|t| !t.id.is_empty())
+    }
+
+    /// Tile drawn with the atlas slot, including the variants of autotiled tiles
+    pub fn by_atlas_slot(&self, slot: usize) -> Option<TileId> {
+        self.tiles()
+            .find(|t| slot >= t.atlas_index && slot < t.atlas_index + t.autotile.map_or(1, Autotile::variant_count))
+            .map(|t| t.id)
+    }
+
+    /// The tile that looks and behaves like `id` flipped horizontally,
+    /// a tile with the mirrored shape and the same solidity and tags
+    pub fn mirrored(&self, id: TileId) -> Option<TileId> {
+        let tile = self.get(id);
+        let shape = tile.shape.mirrored();
+        if shape == tile.shape {
+            return Some(id);
+        }
+
+        self.tiles()
+            .find(|t| t.shape == shape && t.solidity == tile.solidity && t.tags == tile.tags)
+            .map(|t| t.id)
+    }
+
+    /// If the id is empty or belongs to a registered tile
+    pub fn contains(&self, id: TileId) -> bool {
+        id.is_empty() || self.tiles().any(|t| t.id == id)
+    }
+
+    /// Fingerprint of the id to name mapping (FNV-1a), which is all that saved cells depend on.
+    /// Stable across builds, unlike `std::hash`.
+    pub fn hash(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+        self.tiles()
+            .flat_map(|t| t.id.0.to_le_bytes().iter().chain(t.name.as_bytes()).copied().chain(Some(0)).collect::<Vec<u8>>())
+            .fold(FNV_OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+    }
+}
+
+/// The registry shipped in `resources/`, for tests that don't load files at runtime
+#[cfg(test)]
+pub fn test_registry() -> std::sync::Arc<TileRegistry> {
+    std::sync::Arc::new(
+        TileRegistry::from_toml(include_str!("../resources/tiles.toml")).expect("invalid resources/tiles.toml"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::physics::CollisionLayers;
+    use crate::tiles::{Autotile, Neighbours, RegistryError, Solidity, TileId, TileRegistry};
+
+    #[test]
+    fn test_registry_from_toml() {
+        let registry = TileRegistry::from_toml(
+            r#"
+            atlas_size = 2
+
+            [[tile]]
+            id = 1
+            name = "dirt"
+            atlas_index = 3
+            solidity = "solid"
+            friction = 0.8
+            tags = ["natural"]
+
+            [[tile]]
+            id = 3
+            name = "plank"
+            atlas_index = 1
+            solidity = "one_way"
+            "#,
+        )
+        .unwrap();
+
+        let dirt = registry.get(registry.id_by_name("dirt").unwrap());
+        assert_eq!(dirt.id, TileId(1));
+        assert!(dirt.has_tag("natural"));
+        assert_eq!(dirt.collision_layer(), CollisionLayers::WORLD);
+
+        assert_eq!(registry.get(TileId(3)).solidity, Solidity::OneWay);
+        // gaps and unknown ids are empty
+        assert!(!registry.get(TileId(2)).is_solid());
+        assert!(!registry.get(TileId(100)).is_solid());
+        assert_eq!(registry.tiles().count(), 2);
+
+        let out_of_atlas = TileRegistry::from_toml(
+            "atlas_size = 2\n[[tile]]\nid = 1\nname = \"a\"\natlas_index = 4\nsolidity = \"solid\"",
+        );
+        assert!(matches!(out_of_atlas, Err(RegistryError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_autotile_variants() {
+        let all = 0xff;
+        assert_eq!(Autotile::Edges.variant(0), 0);
+        assert_eq!(Autotile::Edges.variant(Neighbours::UP | Neighbours::LEFT), 0b1001);
+        assert_eq!(Autotile::Edges.variant(all), 15);
+        // diagonals don't matter for edges
+        assert_eq!(Autotile::Edges.variant(Neighbours::UP_RIGHT), 0);
+
+        let blob_variants: std::collections::HashSet<usize> = (0..=255u8).map(|m| Autotile::Blob.variant(m)).collect();
+        assert_eq!(blob_variants.len(), 47);
+        assert_eq!(Autotile::Blob.variant(all), 46);
+        // a corner without both adjacent sides looks like no corner
+        assert_eq!(
+            Autotile::Blob.variant(Neighbours::UP | Neighbours::UP_RIGHT),
+            Autotile::Blob.variant(Neighbours::UP)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+/// Simulation ticks per second used by the game and the headless runner
+pub const DEFAULT_TICK_RATE: u32 = 60;
+/// How many ticks one frame may run to catch up before the remaining time is dropped
+pub const DEFAULT_MAX_CATCH_UP_STEPS: u32 = 5;
+
+/// Accumulator for running the simulation with a fixed delta, independent of the frame rate.
+/// Frame time is added with `advance`, which returns how many ticks to run;
+/// the leftover time is exposed as `alpha` to interpolate between the last two ticks.
+#[derive(Debug)]
+pub struct FixedTimestep {
+    tick_delta: f32,
+    max_catch_up_steps: u32,
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32, max_catch_up_steps: u32) -> Self {
+        assert!(tick_rate > 0, "tick rate must be positive");
+        FixedTimestep {
+            tick_delta: 1.0 / tick_rate as f32,
+            max_catch_up_steps,
+            accumulator: 0.0,
+        }
+    }
+
+    pub fn tick_delta(&self) -> f32 {
+        self.tick_delta
+    }
+
+    /// Adds the frame time and returns the number of ticks that should be simulated now.
+    /// If more than `max_catch_up_steps` ticks are due, the surplus is dropped
+    /// so a slow frame can't cause an ever growing backlog.
+    pub fn advance(&mut self, frame_delta: f32) -> u32 {
+        self.accumulator += frame_delta;
+
+        let mut steps = 0;
+        while self.accumulator >= self.tick_delta && steps < self.max_catch_up_steps {
+            self.accumulator -= self.tick_delta;
+            steps += 1;
+        }
+
+        if steps == self.max_catch_up_steps {
+            self.accumulator %= self.tick_delta;
+        }
+
+        steps
+    }
+
+    /// How far the current frame lies between the previous (0.0) and current (1.0) tick
+    pub fn alpha(&self) -> f32 {
+        self.accumulator / self.tick_delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timestep::FixedTimestep;
+    use crate::utils::mostly_eq;
+
+    #[test]
+    fn test_fixed_timestep() {
+        let mut ts = FixedTimestep::new(10, 3);
+
+        assert_eq!(ts.advance(0.05), 0);
+        assert!(mostly_eq(ts.alpha(), 0.5, 0.01));
+
+        assert_eq!(ts.advance(0.1), 1);
+        assert!(mostly_eq(ts.alpha(), 0.5, 0.01));
+
+        // a long hitch only runs the max catch up steps
+        assert_eq!(ts.advance(1.0), 3);
+        assert!(ts.alpha() < 1.0);
+    }
+}
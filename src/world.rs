@@ -1,61 +1,556 @@
 use crate::{
     DebugDrawable,
-    utils::*,
-    physics::RigidBody
+    cam::Cam,
+    physics::{Axis, CollisionLayers},
+    level::LevelError,
+    streaming::ChunkStore,
+    tiles::{Neighbours, TileDef, TileId, TileRegistry},
+    worldgen::ChunkGenerator
 };
-use ggez::graphics::{Color, DrawMode, DrawParam, Image, Mesh, Rect};
+use ggez::graphics::{Color, DrawMode, DrawParam, Image, Mesh, Rect, Text};
 use ggez::{Context, GameError, GameResult};
-use std::collections::HashMap;
-use std::mem::MaybeUninit;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use crate::game::Game;
 
+/// The cell layers of every chunk, in drawing order.
+/// Only the main layer collides, the others are decoration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TileLayer {
+    /// Walls behind the player
+    Background,
+    Main,
+    /// Drawn over the player
+    Foreground,
+}
+
+pub const LAYER_COUNT: usize = 3;
+
+impl TileLayer {
+    pub const ALL: [TileLayer; LAYER_COUNT] = [TileLayer::Background, TileLayer::Main, TileLayer::Foreground];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TileLayer::Background => "background",
+            TileLayer::Main => "main",
+            TileLayer::Foreground => "foreground",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<TileLayer> {
+        TileLayer::ALL.iter().copied().find(|layer| layer.name() == name)
+    }
+}
+
+/// Cells (row by row) of each layer of a chunk, indexed by `TileLayer::index`.
+/// An empty vec stands for a layer without any cells.
+pub type ChunkLayers = [Vec<TileId>; LAYER_COUNT];
+
+/// Layers of several chunks by their chunk coords
+pub type LevelChunks = Vec<((isize, isize), ChunkLayers)>;
+
 pub struct Tilemap {
     /// Maps the "chunk coords (world coords / chunk size)
     chunks: HashMap<(isize, isize), Chunk>,
-    texture_atlas: Image,
+    registry: Arc<TileRegistry>,
+    /// Fills chunks on their first request, without one new chunks are empty
+    generator: Option<Box<dyn ChunkGenerator>>,
+    /// Chunks the generator filled with any tiles, streaming drops them unless they were modified,
+    /// so saving generates them again
+    generated: HashSet<(isize, isize)>,
+    /// Unloads far away chunks, without it every chunk stays loaded
+    streaming: Option<Streaming>,
+    /// Chunks drawn and culled by the last `draw`
+    draw_stats: DrawStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DrawStats {
+    pub drawn: usize,
+    /// Chunks outside the view that were skipped
+    pub culled: usize,
+}
+
+struct Streaming {
+    /// In chunks around the areas passed to `Tilemap::stream`
+    active_radius: isize,
+    /// Modified chunks that are unloaded go here, without a store they stay loaded
+    store: Option<ChunkStore>,
 }
 
 impl Tilemap {
-    pub fn new(texture_atlas: Image, rb: &mut Vec<SharedWeak<RigidBody>>) -> Self {
-        let mut tm = Tilemap {
+    pub fn new(registry: Arc<TileRegistry>) -> Self {
+        Tilemap {
             chunks: HashMap::new(),
-            texture_atlas,
-        };
-        tm
+            registry,
+            generator: None,
+            generated: HashSet::new(),
+            streaming: None,
+            draw_stats: DrawStats::default(),
+        }
+    }
+
+    /// Tilemap whose chunks are generated when they are requested for the first time
+    pub fn with_generator(registry: Arc<TileRegistry>, generator: Box<dyn ChunkGenerator>) -> Self {
+        Tilemap {
+            generator: Some(generator),
+            ..Tilemap::new(registry)
+        }
+    }
+
+    pub fn registry(&self) -> &TileRegistry {
+        &self.registry
     }
 
     pub fn chunks_stored(&self) -> usize {
         self.chunks.len()
     }
 
+    /// Number of chunks that are only in the chunk store
+    pub fn chunks_unloaded(&self) -> usize {
+        self.store()
+            .map_or(0, |store| store.stored().filter(|coords| !self.chunks.contains_key(coords)).count())
+    }
+
+    /// From now on `stream` unloads chunks further than `active_radius` chunks away from its areas
+    pub fn enable_streaming(&mut self, active_radius: isize, store: Option<ChunkStore>) {
+        self.streaming = Some(Streaming { active_radius, store });
+    }
+
+    /// Loads the chunks around the areas (in world coords) and unloads the ones far from all of them.
+    /// Unmodified chunks are dropped, they are generated again when they are requested the next time,
+    /// modified chunks are written to the chunk store.
+    ///
+    /// Bodies collide with the cells of loaded chunks only, so every body that moves must be
+    /// inside one of the areas.
+    pub fn stream(&mut self, areas: &[Rect]) {
+        let radius = match &self.streaming {
+            Some(streaming) => streaming.active_radius,
+            None => return,
+        };
+
+        let ranges: Vec<_> = areas.iter().map(|&area| Chunk::chunk_range(area)).collect();
+        for &((min_x, min_y), (max_x, max_y)) in &ranges {
+            for cy in min_y - radius..=max_y + radius {
+                for cx in min_x - radius..=max_x + radius {
+                    self.request_chunk((cx, cy));
+                }
+            }
+        }
+
+        // one chunk of slack, so chunks at the border aren't unloaded and loaded again all the time
+        let keep_radius = radius + 1;
+        let far: Vec<(isize, isize)> = self
+            .chunks
+            .keys()
+            .copied()
+            .filter(|&(cx, cy)| {
+                !ranges.iter().any(|&((min_x, min_y), (max_x, max_y))| {
+                    cx >= min_x - keep_radius
+                        && cx <= max_x + keep_radius
+                        && cy >= min_y - keep_radius
+                        && cy <= max_y + keep_radius
+                })
+            })
+            .collect();
+
+        for coords in far {
+            self.unload_chunk(coords);
+        }
+    }
+
+    fn unload_chunk(&mut self, coords: (isize, isize)) {
+        let chunk = &self.chunks[&coords];
+        if chunk.modified {
+            let store = match self.streaming.as_mut().and_then(|streaming| streaming.store.as_mut()) {
+                Some(store) => store,
+                None => return,
+            };
+            if let Err(e) = store.save(coords, &chunk.to_layers(), &self.registry) {
+                println!("Failed to unload chunk {:?}: {}", coords, e);
+                return;
+            }
+        }
+
+        self.chunks.remove(&coords);
+    }
+
+    fn store(&self) -> Option<&ChunkStore> {
+        self.streaming.as_ref().and_then(|streaming| streaming.store.as_ref())
+    }
+
+    /// Sets a cell of the main layer
     pub fn set_cell(
         &mut self,
         x: isize,
         y: isize,
-        cell: CellType,
-    ) -> Option<SharedWeak<RigidBody>> {
+        tile: TileId,
+    ) {
+        self.set_layer_cell(TileLayer::Main, x, y, tile);
+    }
+
+    pub fn set_layer_cell(&mut self, layer: TileLayer, x: isize, y: isize, tile: TileId) {
         let (cx, cy) = Chunk::to_chunk_coords(x, y);
-        let cloned_tex = self.texture_atlas.clone();
+        self.request_chunk((cx, cy));
+        if let Some(chunk) = self.chunks.get_mut(&(cx, cy)) {
+            chunk.set_cell(layer, x, y, tile);
+        }
+
+        // autotiled cells of neighbouring chunks may look different now
+        for &(dx, dy) in Neighbours::OFFSETS.iter() {
+            let coords = Chunk::to_chunk_coords(x + dx, y + dy);
+            if coords != (cx, cy) {
+                if let Some(neighbour) = self.chunks.get_mut(&coords) {
+                    neighbour.mesh_needs_update = true;
+                }
+            }
+        }
+    }
+
+    /// Makes sure the chunk at the chunk coords is loaded.
+    /// Chunks that were unloaded come back from the chunk store, new ones are generated.
+    pub fn request_chunk(&mut self, (cx, cy): (isize, isize)) {
+        if self.chunks.contains_key(&(cx, cy)) {
+            return;
+        }
+
+        let mut chunk = Chunk::new(cx, cy);
+        let stored = self.store().filter(|store| store.contains((cx, cy)));
+        match stored.map(|store| store.load((cx, cy), &self.registry)) {
+            Some(Ok(layers)) => chunk.set_layers(&layers),
+            Some(Err(e)) => {
+                println!("Failed to reload chunk {:?}, generating it instead: {}", (cx, cy), e);
+                self.generate(&mut chunk);
+            }
+            None => self.generate(&mut chunk),
+        }
+        // empty chunks, like most of the sky, would only be generated again to be dropped when saving
+        let is_empty = TileLayer::ALL.iter().all(|&layer| chunk.is_layer_empty(layer));
+        if self.generator.is_some() && !is_empty {
+            self.generated.insert((cx, cy));
+        }
+
+        self.chunks.insert((cx, cy), chunk);
+        self.mark_neighbours_outdated((cx, cy));
+    }
+
+    fn generate(&self, chunk: &mut Chunk) {
+        if let Some(generator) = &self.generator {
+            for &layer in TileLayer::ALL.iter() {
+                generator.generate(chunk.x, chunk.y, layer, &mut chunk.layers[layer.index()]);
+            }
+        }
+    }
+
+    /// Requests every chunk that overlaps the area (in world coords)
+    pub fn request_area(&mut self, area: Rect) {
+        for coords in Tilemap::chunks_in_area(area) {
+            self.request_chunk(coords);
+        }
+    }
+
+    /// Coords of every chunk that overlaps the area (in world coords), loaded or not
+    pub fn chunks_in_area(area: Rect) -> Vec<(isize, isize)> {
+        let ((min_x, min_y), (max_x, max_y)) = Chunk::chunk_range(area);
+        (min_y..=max_y)
+            .flat_map(|cy| (min_x..=max_x).map(move |cx| (cx, cy)))
+            .collect()
+    }
+
+    /// Returns the tile id of the main layer at the world coords, cells of chunks that don't exist are empty
+    pub fn get_cell(&self, x: isize, y: isize) -> TileId {
+        self.get_layer_cell(TileLayer::Main, x, y)
+    }
+
+    pub fn get_layer_cell(&self, layer: TileLayer, x: isize, y: isize) -> TileId {
+        self.chunks
+            .get(&Chunk::to_chunk_coords(x, y))
+            .map_or(TileId::EMPTY, |chunk| chunk.get_cell(layer, x, y))
+    }
+
+    /// Definition of the tile of the main layer at the world coords, this is what bodies collide with
+    pub fn get_tile(&self, x: isize, y: isize) -> &TileDef {
+        self.registry.get(self.get_cell(x, y))
+    }
+
+    /// Atlas slot the cell is drawn with, autotiled tiles pick it from their neighbours in the same layer
+    pub fn atlas_index(&self, layer: TileLayer, x: isize, y: isize) -> usize {
+        let tile = self.registry.get(self.get_layer_cell(layer, x, y));
+        if tile.autotile.is_none() {
+            return tile.atlas_index;
+        }
+
+        let neighbours = Neighbours::OFFSETS
+            .iter()
+            .enumerate()
+            .filter(|(_, &(dx, dy))| tile.connects_to(self.registry.get(self.get_layer_cell(layer, x + dx, y + dy))))
+            .fold(0u8, |mask, (bit, _)| mask | 1 << bit);
+
+        tile.atlas_index_for(neighbours)
+    }
+
+    /// Moves `rect` by `distance` along `axis` through the grid (other axis stays fixed).
+    /// Returns the distance it can travel until it touches the first cell that blocks this motion
+    /// and whose layer is in `mask`, and that tile, or `None` if the whole way is free.
+    /// Cells the rect already overlaps are ignored, so a body can't get stuck on them.
+    ///
+    /// When moving sideways or down, the rect may be lifted by up to `step_up` onto a surface
+    /// it sinks into, which is how bodies walk up slopes (moving down the returned distance is
+    /// negative then).
+    pub fn sweep(
+        &self,
+        rect: Rect,
+        axis: Axis,
+        distance: f32,
+        step_up: f32,
+        mask: CollisionLayers,
+    ) -> Option<(f32, &TileDef)> {
+        match axis {
+            Axis::X if distance == 0.0 => None,
+            Axis::X => self.sweep_x(rect, distance, step_up, mask),
+            Axis::Y if distance < 0.0 => self.sweep_up(rect, distance, mask),
+            Axis::Y if distance == 0.0 && step_up == 0.0 => None,
+            Axis::Y => self.sweep_down(rect, distance, step_up, mask),
+        }
+    }
+
+    fn sweep_x(&self, rect: Rect, distance: f32, step_up: f32, mask: CollisionLayers) -> Option<(f32, &TileDef)> {
+        let rows = (rect.y + SWEEP_EPS).floor() as isize..(rect.bottom() - SWEEP_EPS).ceil() as isize;
+
+        // the columns the front edge enters, nearest first
+        let columns: Vec<isize> = if distance > 0.0 {
+            let front = rect.right();
+            ((front - SWEEP_EPS).ceil() as isize..=(front + distance - SWEEP_EPS).floor() as isize)
+                .collect()
+        } else {
+            ((rect.x + distance + SWEEP_EPS).floor() as isize..(rect.x + SWEEP_EPS).floor() as isize)
+                .rev()
+                .collect()
+        };
+
+        for column in columns {
+            let blocking = rows.clone().map(|row| (row, self.get_tile(column, row))).find(|&(row, cell)| {
+                if !cell.blocks(Axis::X, distance) || !mask.intersects(cell.collision_layer()) {
+                    return false;
+                }
+
+                // height of the side the rect runs into
+                let edge_height = if distance > 0.0 {
+                    cell.surface_height(0.0)
+                } else {
+                    cell.surface_height(1.0)
+                };
+                let edge_top = (row + 1) as f32 - edge_height;
+
+                edge_height > 0.0 && rect.bottom() > edge_top + step_up + SWEEP_EPS
+            });
+
+            if let Some((_, cell)) = blocking {
+                let free = if distance > 0.0 {
+                    column as f32 - rect.right()
+                } else {
+                    (column + 1) as f32 - rect.x
+                };
+                return Some((free, cell));
+            }
+        }
+
+        None
+    }
+
+    fn sweep_down(&self, rect: Rect, distance: f32, step_up: f32, mask: CollisionLayers) -> Option<(f32, &TileDef)> {
+        let front = rect.bottom();
+        let columns = (rect.x + SWEEP_EPS).floor() as isize..(rect.right() - SWEEP_EPS).ceil() as isize;
+        let rows = (front - step_up - SWEEP_EPS).floor() as isize..=(front + distance - SWEEP_EPS).floor() as isize;
+
+        for row in rows {
+            let mut nearest: Option<(f32, &TileDef)> = None;
+
+            for column in columns.clone() {
+                let cell = self.get_tile(column, row);
+                if !cell.blocks(Axis::Y, distance.max(step_up)) || !mask.intersects(cell.collision_layer()) {
+                    continue;
+                }
+
+                // the highest point of the surface under the rect, slopes are touched by a corner
+                let from = rect.x.max(column as f32) - column as f32;
+                let to = rect.right().min((column + 1) as f32) - column as f32;
+                let height = cell.surface_height(from).max(cell.surface_height(to));
+                let surface = (row + 1) as f32 - height;
+
+                if surface < front - step_up - SWEEP_EPS || surface > front + distance - SWEEP_EPS {
+                    continue;
+                }
+
+                let free = surface - front;
+                if nearest.map_or(true, |(nearest_free, _)| free < nearest_free) {
+                    nearest = Some((free, cell));
+                }
+            }
+
+            // surfaces of lower rows can't be any higher
+            if nearest.is_some() {
+                return nearest;
+            }
+        }
+
+        None
+    }
+
+    fn sweep_up(&self, rect: Rect, distance: f32, mask: CollisionLayers) -> Option<(f32, &TileDef)> {
+        let columns = (rect.x + SWEEP_EPS).floor() as isize..(rect.right() - SWEEP_EPS).ceil() as isize;
+
+        // all cells are solid up to their bottom edge, so only whole rows matter
+        let rows = ((rect.y + distance + SWEEP_EPS).floor() as isize..(rect.y + SWEEP_EPS).floor() as isize).rev();
+
+        for row in rows {
+            let blocking = columns
+                .clone()
+                .map(|column| self.get_tile(column, row))
+                .find(|cell| cell.blocks(Axis::Y, distance) && mask.intersects(cell.collision_layer()));
+
+            if let Some(cell) = blocking {
+                return Some(((row + 1) as f32 - rect.y, cell));
+            }
+        }
+
+        None
+    }
+
+    /// Draws the layers of the chunks the cam sees, meshes of chunks outside its view aren't rebuilt either.
+    /// All chunks are drawn for one layer before the next one starts.
+    pub fn draw(&mut self, ctx: &mut Context, texture_atlas: &Image, cam: &Cam, layers: &[TileLayer]) -> GameResult<()> {
+        let visible = self.visible_chunks(cam.visible_rect());
+        self.draw_stats = DrawStats {
+            drawn: visible.len(),
+            culled: self.chunks.len() - visible.len(),
+        };
+
+        // meshes are built here and not by the chunks, autotiling needs the cells around them
+        for &coords in &visible {
+            if !self.chunks[&coords].mesh_needs_update {
+                continue;
+            }
+            let atlas_indices = self.chunk_atlas_indices(coords);
+            if let Some(chunk) = self.chunks.get_mut(&coords) {
+                chunk.update_mesh(ctx, texture_atlas, &atlas_indices, self.registry.atlas_size())?;
+            }
+        }
+
+        for &layer in layers {
+            for coords in &visible {
+                self.chunks[coords].draw(ctx, layer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn draw_stats(&self) -> DrawStats {
+        self.draw_stats
+    }
+
+    /// Coords of the loaded chunks that overlap the view (in world coords)
+    fn visible_chunks(&self, view: Rect) -> Vec<(isize, isize)> {
+        self.chunks
+            .values()
+            .filter(|chunk| chunk.world_rect().overlaps(&view))
+            .map(|chunk| (chunk.x, chunk.y))
+            .collect()
+    }
+}
+
+impl Tilemap {
+    /// Layers of every chunk that isn't completely empty, sorted by chunk coords.
+    /// Includes the chunks that are only in the chunk store and the generated ones that were unloaded.
+    pub(crate) fn non_empty_chunks(&self) -> Result<LevelChunks, LevelError> {
+        let mut chunks: Vec<_> = self
+            .chunks
+            .iter()
+            .map(|(&coords, chunk)| (coords, chunk.to_layers()))
+            .collect();
+
+        let store = self.store();
+        if let Some(store) = store {
+            for coords in store.stored().filter(|coords| !self.chunks.contains_key(coords)) {
+                chunks.push((coords, store.load(coords, &self.registry)?));
+            }
+        }
+
+        let dropped = self.generated.iter().filter(|&&coords| {
+            !self.chunks.contains_key(&coords) && !store.map_or(false, |store| store.contains(coords))
+        });
+        for &(cx, cy) in dropped {
+            let mut chunk = Chunk::new(cx, cy);
+            self.generate(&mut chunk);
+            chunks.push(((cx, cy), chunk.to_layers()));
+        }
+
+        chunks.retain(|(_, layers)| layers.iter().any(|cells| !cells.is_empty()));
+        chunks.sort_by_key(|&(coords, _)| coords);
+        Ok(chunks)
+    }
+
+    /// Layers of a loaded chunk
+    pub(crate) fn chunk_layers(&self, coords: (isize, isize)) -> Option<ChunkLayers> {
+        self.chunks.get(&coords).map(Chunk::to_layers)
+    }
+
+    /// Replaces all cells of the chunk at the chunk coords
+    pub(crate) fn set_chunk(&mut self, (cx, cy): (isize, isize), layers: &ChunkLayers) {
         let chunk = self
             .chunks
             .entry((cx, cy))
             .or_insert_with(|| Chunk::new(cx, cy));
-
-        chunk.set_cell(x, y, cell)
+        chunk.set_layers(layers);
+        chunk.mesh_needs_update = true;
+        chunk.modified = true;
+        self.mark_neighbours_outdated((cx, cy));
     }
 
-    pub fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
-        for chunk in self.chunks.values_mut() {
-            chunk.draw(ctx, &self.texture_atlas)?;
+    /// The border cells of the neighbours autotile against the cells of the chunk
+    fn mark_neighbours_outdated(&mut self, (cx, cy): (isize, isize)) {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if let Some(neighbour) = self.chunks.get_mut(&(cx + dx, cy + dy)) {
+                    neighbour.mesh_needs_update = true;
+                }
+            }
         }
+    }
 
-        //self.chunks.get_mut(&(0, 0)).unwrap().draw(ctx, &self.texture_atlas)?;
-
-        Ok(())
+    /// `atlas_index` of every cell of each layer of the chunk, empty for layers without cells
+    fn chunk_atlas_indices(&self, (cx, cy): (isize, isize)) -> Vec<Vec<usize>> {
+        let chunk = &self.chunks[&(cx, cy)];
+        TileLayer::ALL
+            .iter()
+            .map(|&layer| {
+                if chunk.is_layer_empty(layer) {
+                    return vec![];
+                }
+                (0..CHUNK_SIZE)
+                    .flat_map(|y| (0..CHUNK_SIZE).map(move |x| (x, y)))
+                    .map(|(x, y)| self.atlas_index(layer, cx * CHUNK_SIZE + x, cy * CHUNK_SIZE + y))
+                    .collect()
+            })
+            .collect()
     }
 }
 
 impl DebugDrawable for Tilemap {
+    fn debug_draw_screenspace(&mut self, ctx: &mut Context, game: &Game) -> GameResult<()> {
+        let stats = Text::new(format!("chunks drawn: {}, culled: {}", self.draw_stats.drawn, self.draw_stats.culled));
+        ggez::graphics::draw(
+            ctx,
+            &stats,
+            DrawParam::default().dest(cgmath::Point2::new(10.0, game.cam.last_vp_size.y - 25.0)),
+        )
+    }
+
     fn debug_draw_worldspace(&mut self, ctx: &mut Context, game: &Game) -> GameResult<()> {
         let chunk_box = ggez::graphics::Mesh::new_rectangle(
             ctx,
@@ -78,78 +573,43 @@ impl DebugDrawable for Tilemap {
     }
 }
 
-const CHUNK_SIZE: isize = 16;
-
-#[derive(Clone, Copy, PartialEq, Debug)]
-pub enum CellType {
-    Empty,
-    Stone,
-}
-
-#[derive(Clone, Debug)]
-struct Cell {
-    cell_type: CellType,
-    rb: Option<Shared<RigidBody>>,
-}
-
-impl Cell {
-    pub const fn empty() -> Cell {
-        Cell {
-            cell_type: CellType::Empty,
-            rb: None,
-        }
-    }
-
-    pub fn is_empty(&self) -> bool {
-        if CellType::Empty == self.cell_type {
-            true
-        } else {
-            false
-        }
-    }
-}
+/// Width and height of a chunk in cells
+pub const CHUNK_SIZE: isize = 16;
+pub const CELLS_PER_CHUNK: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+/// Tolerance of the tile sweeps, so touching surfaces don't count as overlapping
+const SWEEP_EPS: f32 = 1e-4;
 
 struct Chunk {
-    cells: [Cell; CHUNK_SIZE as usize * CHUNK_SIZE as usize],
+    /// Cells row by row, indexed by `TileLayer::index`
+    layers: [[TileId; CELLS_PER_CHUNK]; LAYER_COUNT],
     mesh_needs_update: bool,
-    sprites: Option<Mesh>,
+    /// Changed since it was generated or loaded from the chunk store, so it can't just be dropped
+    modified: bool,
+    /// One mesh per layer, `None` if the layer is empty
+    sprites: [Option<Mesh>; LAYER_COUNT],
     x: isize,
     y: isize,
 }
 
 impl Chunk {
     pub fn new(x: isize, y: isize) -> Chunk {
-        // needed because Cell cant implement copy because of the rb reference
-        // this is valid because Cell::empty() is just static data
-        let mut unsafe_cells: [MaybeUninit<Cell>; (CHUNK_SIZE * CHUNK_SIZE) as usize] =
-            unsafe { MaybeUninit::uninit().assume_init() };
-
-        for cell in unsafe_cells.iter_mut() {
-            *cell = MaybeUninit::new(Cell::empty());
-        }
-
         Chunk {
-            cells: unsafe { std::mem::transmute(unsafe_cells) },
+            layers: [[TileId::EMPTY; CELLS_PER_CHUNK]; LAYER_COUNT],
             mesh_needs_update: true,
-            sprites: None,
+            modified: false,
+            sprites: [None, None, None],
             x,
             y,
         }
     }
 
-    pub fn draw(&mut self, ctx: &mut Context, texture_atlas: &Image) -> GameResult<()> {
+    pub fn draw(&self, ctx: &mut Context, layer: TileLayer) -> GameResult<()> {
         /*
         let img = Image::from_rgba8(ctx, 2, 2,
                                     &[255, 0, 0, 125, 0, 255, 0, 0, 255, 255, 0, 0, 0, 255, 255,
                                         255])?;
         */
-        if self.mesh_needs_update {
-            self.update_mesh(ctx, texture_atlas)?;
-
-            self.mesh_needs_update = false;
-        }
-
-        if let Some(sprites) = &self.sprites {
+        if let Some(sprites) = &self.sprites[layer.index()] {
             ggez::graphics::draw(
                 ctx,
                 sprites,
@@ -163,27 +623,57 @@ impl Chunk {
         Ok(())
     }
 
-    pub fn update_mesh(&mut self, ctx: &mut Context, texture_atlas: &Image) -> GameResult<()> {
+    /// Rebuilds the meshes, `atlas_indices` holds the atlas slot of each cell of each layer
+    pub fn update_mesh(
+        &mut self,
+        ctx: &mut Context,
+        texture_atlas: &Image,
+        atlas_indices: &[Vec<usize>],
+        atlas_size: usize,
+    ) -> GameResult<()> {
+        println!("Chunk @ ({},{}): update_mesh", self.x, self.y);
+        for &layer in TileLayer::ALL.iter() {
+            self.update_layer_mesh(ctx, texture_atlas, layer, &atlas_indices[layer.index()], atlas_size)?;
+        }
+        self.mesh_needs_update = false;
+
+        Ok(())
+    }
+
+    fn update_layer_mesh(
+        &mut self,
+        ctx: &mut Context,
+        texture_atlas: &Image,
+        layer: TileLayer,
+        atlas_indices: &[usize],
+        atlas_size: usize,
+    ) -> GameResult<()> {
         const QUAD_VERT_OFFSETS: [[f32; 2]; 4] = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
 
-        println!("Chunk @ ({},{}): update_mesh", self.x, self.y);
-        //println!("{:?}", &self.cells);
+        let cells = &self.layers[layer.index()];
+        let sprites = &mut self.sprites[layer.index()];
+        //println!("{:?}", &cells[..]);
         // check if at least one cell is not empty
-        if self.cells.iter().any(|c| c.cell_type != CellType::Empty) {
+        if cells.iter().any(|c| !c.is_empty()) {
             // create mesh
             let mut verts = Vec::new();
             let mut indices: Vec<u32> = Vec::new();
 
-            for (idx, cell) in self.cells.iter().enumerate().filter(|c| !c.1.is_empty()) {
+            for (idx, _) in cells.iter().enumerate().filter(|c| !c.1.is_empty()) {
+                let atlas_index = atlas_indices[idx];
                 let idx = idx as isize;
                 let y = idx / CHUNK_SIZE;
                 let x = idx - (y * CHUNK_SIZE);
 
+                let atlas_x = (atlas_index % atlas_size) as f32;
+                let atlas_y = (atlas_index / atlas_size) as f32;
+                let uv_scale = 1.0 / atlas_size as f32;
+
                 // create four vertices
                 for [qx, qy] in QUAD_VERT_OFFSETS.iter() {
                     verts.push(ggez::graphics::Vertex {
                         pos: [*qx + x as f32, *qy + y as f32],
-                        uv: [*qx * 0.25, *qy * 0.25],
+                        uv: [(atlas_x + *qx) * uv_scale, (atlas_y + *qy) * uv_scale],
                         color: [1.0; 4],
                     })
                 }
@@ -202,10 +692,10 @@ impl Chunk {
                 );
             }
 
-            if let Some(mesh) = &mut self.sprites {
+            if let Some(mesh) = sprites {
                 mesh.set_vertices(ctx, &verts, &indices);
             } else {
-                self.sprites = Some(Mesh::from_raw(
+                *sprites = Some(Mesh::from_raw(
                     ctx,
                     &verts,
                     &indices,
@@ -213,7 +703,7 @@ impl Chunk {
                 )?);
             }
         } else {
-            self.sprites = None;
+            *sprites = None;
         }
 
         Ok(())
@@ -221,38 +711,302 @@ impl Chunk {
 
     pub fn set_cell(
         &mut self,
+        layer: TileLayer,
         x: isize,
         y: isize,
-        cell_type: CellType,
-    ) -> Option<SharedWeak<RigidBody>> {
-        let loc_x = x - self.x * CHUNK_SIZE;
-        let loc_y = y - self.y * CHUNK_SIZE;
-        let cell = &mut self.cells[(loc_y * CHUNK_SIZE + loc_x) as usize];
-        cell.cell_type = cell_type;
-
-        match cell_type {
-            CellType::Empty => None,
-            _ => {
-                let rb = shared(RigidBody::new(
-                    (x as f32 - 0.05, y as f32 - 0.05).into(),
-                    (1.1, 1.1).into(),
-                    None,
-                ));
-                let ret = Some(Shared::downgrade(&rb));
-                cell.rb = Some(rb);
-                ret
+        tile: TileId,
+    ) {
+        let idx = self.local_index(x, y);
+        self.layers[layer.index()][idx] = tile;
+        self.mesh_needs_update = true;
+        self.modified = true;
+    }
+
+    pub fn get_cell(&self, layer: TileLayer, x: isize, y: isize) -> TileId {
+        self.layers[layer.index()][self.local_index(x, y)]
+    }
+
+    fn is_layer_empty(&self, layer: TileLayer) -> bool {
+        self.layers[layer.index()].iter().all(|c| c.is_empty())
+    }
+
+    /// Copies of the layers, empty ones become empty vecs
+    fn to_layers(&self) -> ChunkLayers {
+        let mut layers = ChunkLayers::default();
+        for &layer in TileLayer::ALL.iter() {
+            if !self.is_layer_empty(layer) {
+                layers[layer.index()] = self.layers[layer.index()].to_vec();
+            }
+        }
+        layers
+    }
+
+    /// Replaces all cells, every layer must either be empty or have exactly `CELLS_PER_CHUNK` cells
+    fn set_layers(&mut self, layers: &ChunkLayers) {
+        for (cells, new_cells) in self.layers.iter_mut().zip(layers.iter()) {
+            if new_cells.is_empty() {
+                *cells = [TileId::EMPTY; CELLS_PER_CHUNK];
+            } else {
+                cells.copy_from_slice(new_cells);
             }
         }
     }
 
+    /// Index into `cells` for world cell coords inside this chunk
+    fn local_index(&self, x: isize, y: isize) -> usize {
+        let loc_x = x - self.x * CHUNK_SIZE;
+        let loc_y = y - self.y * CHUNK_SIZE;
+        (loc_y * CHUNK_SIZE + loc_x) as usize
+    }
+
     fn to_chunk_coords(mut x: isize, mut y: isize) -> (isize, isize) {
         x = x - x.rem_euclid(CHUNK_SIZE);
         y = y - y.rem_euclid(CHUNK_SIZE);
         (x / CHUNK_SIZE, y / CHUNK_SIZE)
     }
 
+    /// Smallest and largest chunk coords of the chunks that overlap the area (in world coords)
+    fn chunk_range(area: Rect) -> ((isize, isize), (isize, isize)) {
+        (
+            Chunk::to_chunk_coords(area.x.floor() as isize, area.y.floor() as isize),
+            Chunk::to_chunk_coords(area.right().ceil() as isize, area.bottom().ceil() as isize),
+        )
+    }
+
+    fn world_rect(&self) -> Rect {
+        let base = self.base_world_point();
+        Rect::new(base.x, base.y, CHUNK_SIZE as f32, CHUNK_SIZE as f32)
+    }
+
     fn base_world_point(&self) -> cgmath::Point2<f32> {
         let s = CHUNK_SIZE as f32;
         cgmath::Point2::new(self.x as f32 * s, self.y as f32 * s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::physics::{Axis, CollisionLayers, MIN_STEP_UP};
+    use crate::utils::mostly_eq;
+    use crate::tiles::test_registry;
+    use crate::streaming::ChunkStore;
+    use crate::tiles::TileId;
+    use crate::world::{TileLayer, Tilemap};
+    use crate::worldgen::NoiseGenerator;
+    use crate::level::{self, LevelFormat};
+    use ggez::graphics::Rect;
+
+    #[test]
+    fn test_sweep_ignores_floor_seams() {
+        let mut tiles = Tilemap::new(test_registry());
+        let stone = tiles.registry().id_by_name("stone").unwrap();
+        for x in -4..4 {
+            tiles.set_cell(x, 0, stone);
+        }
+
+        // resting on the floor, crossing the seams and the chunk border at x = 0
+        let on_floor = Rect::new(-2.5, -1.0, 1.0, 1.0);
+        assert_eq!(tiles.sweep(on_floor, Axis::X, 3.0, 0.0, CollisionLayers::ALL), None);
+
+        let falling = Rect::new(-2.5, -3.0, 1.0, 1.0);
+        let (free, cell) = tiles.sweep(falling, Axis::Y, 5.0, 0.0, CollisionLayers::ALL).expect("floor not hit");
+        assert!(mostly_eq(free, 2.0, 0.001));
+        assert_eq!(cell.id, stone);
+    }
+
+    #[test]
+    fn test_platform_is_one_way() {
+        let mut tiles = Tilemap::new(test_registry());
+        let platform = tiles.registry().id_by_name("platform").unwrap();
+        for x in 0..4 {
+            tiles.set_cell(x, 5, platform);
+        }
+
+        let above = Rect::new(1.0, 3.0, 1.0, 1.0);
+        let (free, _) = tiles.sweep(above, Axis::Y, 2.0, 0.0, CollisionLayers::ALL).expect("platform not hit");
+        assert!(mostly_eq(free, 1.0, 0.001));
+
+        // dropping through
+        let without_platforms = CollisionLayers::ALL.without(CollisionLayers::PLATFORM);
+        assert_eq!(tiles.sweep(above, Axis::Y, 2.0, 0.0, without_platforms), None);
+
+        // jumping up through it and walking inside it
+        let below = Rect::new(1.0, 6.0, 1.0, 1.0);
+        assert_eq!(tiles.sweep(below, Axis::Y, -2.0, 0.0, CollisionLayers::ALL), None);
+        let inside = Rect::new(1.0, 4.5, 1.0, 1.0);
+        assert_eq!(tiles.sweep(inside, Axis::X, 2.0, 0.0, CollisionLayers::ALL), None);
+        assert_eq!(tiles.sweep(inside, Axis::Y, 0.2, 0.0, CollisionLayers::ALL), None);
+    }
+
+    #[test]
+    fn test_walk_up_slope() {
+        let registry = test_registry();
+        let stone = registry.id_by_name("stone").unwrap();
+        let slope = registry.id_by_name("stone_slope_right").unwrap();
+        let gentle_low = registry.id_by_name("stone_slope_right_low").unwrap();
+        let gentle_high = registry.id_by_name("stone_slope_right_high").unwrap();
+
+        // the 45° slope and the two cells of the 22.5° one
+        for ramp in [vec![slope], vec![gentle_low, gentle_high]].iter() {
+            let mut tiles = Tilemap::new(registry.clone());
+            for x in 0..12 {
+                tiles.set_cell(x, 10, stone);
+            }
+            for (x, &cell) in ramp.iter().enumerate() {
+                tiles.set_cell(4 + x as isize, 9, cell);
+            }
+            for x in 4 + ramp.len() as isize..12 {
+                tiles.set_cell(x, 9, stone);
+            }
+
+            // walks into the slope from the flat floor
+            let mut rect = Rect::new(2.5, 9.0, 1.0, 1.0);
+            while rect.x < 7.0 {
+                let step: f32 = 0.1;
+                // as much as the physics allows
+                let step_up = step.max(MIN_STEP_UP);
+                match tiles.sweep(rect, Axis::X, step, step_up, CollisionLayers::ALL) {
+                    Some((free, _)) => panic!("blocked at x = {} after {}", rect.x, free),
+                    None => rect.x += step,
+                }
+                if let Some((free, _)) = tiles.sweep(rect, Axis::Y, 0.01, step_up, CollisionLayers::ALL) {
+                    rect.y += free;
+                }
+
+                // halfway up the gentle ramp the corner rests on its surface
+                if ramp.len() == 2 && mostly_eq(rect.x, 4.0, 0.01) {
+                    assert!(mostly_eq(rect.bottom(), 10.0 - 0.414, 0.01), "bottom at {}", rect.bottom());
+                }
+            }
+
+            // now stands on the upper floor
+            assert!(mostly_eq(rect.bottom(), 9.0, 0.01));
+        }
+    }
+
+    #[test]
+    fn test_half_block_is_a_step() {
+        let mut tiles = Tilemap::new(test_registry());
+        let half = tiles.registry().id_by_name("stone_half").unwrap();
+        tiles.set_cell(3, 9, half);
+
+        let walking = Rect::new(1.5, 9.0, 1.0, 1.0);
+        let (free, _) = tiles.sweep(walking, Axis::X, 1.0, 0.1, CollisionLayers::ALL).expect("walked through the half block");
+        assert!(mostly_eq(free, 0.5, 0.001));
+
+        let falling = Rect::new(2.5, 7.0, 1.0, 1.0);
+        let (free, _) = tiles.sweep(falling, Axis::Y, 2.0, 0.0, CollisionLayers::ALL).expect("fell through the half block");
+        assert!(mostly_eq(free, 1.5, 0.001));
+    }
+
+    #[test]
+    fn test_autotile_across_chunk_border() {
+        let mut tiles = Tilemap::new(test_registry());
+        let stone = tiles.registry().id_by_name("stone").unwrap();
+        let first_variant = tiles.registry().get(stone).atlas_index;
+
+        // x = 15 and 16 are in different chunks
+        tiles.set_cell(15, 3, stone);
+        assert_eq!(tiles.atlas_index(TileLayer::Main, 15, 3), first_variant);
+
+        for chunk in tiles.chunks.values_mut() {
+            chunk.mesh_needs_update = false;
+        }
+        tiles.set_cell(16, 3, stone);
+        assert!(tiles.chunks[&(0, 0)].mesh_needs_update, "neighbour chunk not marked for a mesh update");
+
+        // right neighbour of the left cell, left neighbour of the right cell
+        assert_eq!(tiles.atlas_index(TileLayer::Main, 15, 3), first_variant + 0b0010);
+        assert_eq!(tiles.atlas_index(TileLayer::Main, 16, 3), first_variant + 0b1000);
+    }
+
+    #[test]
+    fn test_streaming_unloads_and_restores_chunks() {
+        let registry = test_registry();
+        let generator = NoiseGenerator::new(3, &registry);
+        let surface_y = generator.surface_y(5);
+        let mut tiles = Tilemap::with_generator(registry, Box::new(generator));
+        tiles.enable_streaming(1, Some(ChunkStore::new().unwrap()));
+
+        let home = Rect::new(0.0, surface_y as f32, 1.0, 1.0);
+        tiles.stream(&[home]);
+        let generated = tiles.get_cell(8, surface_y + 3);
+        tiles.set_cell(5, surface_y - 1, TileId(2));
+        let loaded = tiles.chunks_stored();
+        let near_home = |tiles: &Tilemap| {
+            tiles.non_empty_chunks().unwrap().iter().filter(|(coords, _)| coords.0 < 10).count()
+        };
+        // one chunk is all sky, it is neither saved nor remembered as generated
+        assert_eq!((loaded, near_home(&tiles), tiles.generated.len()), (9, 8, 8));
+
+        // only the modified chunk goes to disk, the generated ones are dropped
+        tiles.stream(&[Rect::new(1000.0, surface_y as f32, 1.0, 1.0)]);
+        assert_eq!(tiles.get_cell(5, surface_y - 1), TileId::EMPTY);
+        assert_eq!(tiles.chunks_unloaded(), 1);
+        // but still saved
+        assert_eq!(near_home(&tiles), 8);
+
+        tiles.stream(&[home]);
+        assert_eq!(tiles.get_cell(5, surface_y - 1), TileId(2));
+        assert_eq!(tiles.get_cell(8, surface_y + 3), generated);
+        assert_eq!(tiles.chunks_unloaded(), 0);
+        assert!(tiles.chunks_stored() >= loaded);
+    }
+
+    #[test]
+    fn test_saving_includes_unloaded_generated_chunks() {
+        let registry = test_registry();
+        let generator = NoiseGenerator::new(3, &registry);
+        let surface_y = generator.surface_y(5);
+        let mut tiles = Tilemap::with_generator(registry.clone(), Box::new(generator));
+        tiles.enable_streaming(1, Some(ChunkStore::new().unwrap()));
+
+        tiles.stream(&[Rect::new(0.0, surface_y as f32, 1.0, 1.0)]);
+        tiles.set_cell(5, surface_y - 1, TileId(2));
+        let explored: Vec<_> = (-16..16).map(|x| (x, tiles.get_cell(x, surface_y + 3))).collect();
+
+        tiles.stream(&[Rect::new(1000.0, surface_y as f32, 1.0, 1.0)]);
+        let saved = level::encode(&tiles, LevelFormat::Binary).unwrap();
+
+        // the level has no generator, everything explored must be in the file
+        let loaded = level::decode(&saved, registry).unwrap();
+        assert_eq!(loaded.get_cell(5, surface_y - 1), TileId(2));
+        for (x, tile) in explored {
+            assert_eq!(loaded.get_cell(x, surface_y + 3), tile, "cell at x = {} was not saved", x);
+        }
+    }
+
+    #[test]
+    fn test_only_main_layer_collides() {
+        let mut tiles = Tilemap::new(test_registry());
+        let stone = tiles.registry().id_by_name("stone").unwrap();
+        for x in 0..4 {
+            tiles.set_layer_cell(TileLayer::Background, x, 5, stone);
+            tiles.set_layer_cell(TileLayer::Foreground, x, 5, stone);
+        }
+
+        let above = Rect::new(1.0, 3.0, 1.0, 1.0);
+        assert_eq!(tiles.sweep(above, Axis::Y, 3.0, 0.0, CollisionLayers::ALL), None);
+        assert!(tiles.get_cell(1, 5).is_empty());
+
+        // autotiling only looks at the same layer
+        let first_variant = tiles.registry().get(stone).atlas_index;
+        tiles.set_cell(1, 4, stone);
+        assert_eq!(tiles.atlas_index(TileLayer::Main, 1, 4), first_variant);
+        assert_eq!(tiles.atlas_index(TileLayer::Background, 1, 5), first_variant + 0b1010);
+    }
+
+    #[test]
+    fn test_visible_chunks() {
+        let mut tiles = Tilemap::new(test_registry());
+        let stone = tiles.registry().id_by_name("stone").unwrap();
+        for x in -40..40 {
+            tiles.set_cell(x, 0, stone);
+        }
+
+        // chunks -3..=2 exist, the view covers parts of -1 and 0
+        let mut visible = tiles.visible_chunks(Rect::new(-10.0, -5.0, 25.0, 10.0));
+        visible.sort();
+        assert_eq!(visible, vec![(-1, 0), (0, 0)]);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::tiles::{TileId, TileRegistry};
+use crate::world::{TileLayer, CHUNK_SIZE};
+
+/// Fills chunks that are requested for the first time.
+/// Implementations must only depend on the chunk coords and their own settings,
+/// so every machine that generates a chunk ends up with the same cells.
+pub trait ChunkGenerator {
+    /// Writes the cells (row by row) of a layer of the chunk at the chunk coords, `cells` starts out empty
+    fn generate(&self, chunk_x: isize, chunk_y: isize, layer: TileLayer, cells: &mut [TileId]);
+}
+
+/// Default terrain: rolling hills of stone with caves and ore pockets below the surface,
+/// and walls in the background of everything below the surface.
+///
+/// The noise only uses integer hashing and float additions and multiplications,
+/// which give the same results on every platform (unlike `sin` or `exp`).
+pub struct NoiseGenerator {
+    seed: u64,
+    stone: TileId,
+    ore: TileId,
+    wall: TileId,
+}
+
+/// Row the surface oscillates around
+const GROUND_LEVEL: f32 = 0.0;
+/// How far the surface reaches above and below the ground level, in cells
+const HILL_HEIGHT: f32 = 12.0;
+/// Width of the biggest hills, in cells
+const HILL_WIDTH: f32 = 48.0;
+
+const CAVE_SCALE: f32 = 12.0;
+const CAVE_THRESHOLD: f32 = 0.62;
+/// Caves don't break through the surface, the top rows are always solid
+const CAVE_MIN_DEPTH: isize = 6;
+
+const ORE_SCALE: f32 = 3.0;
+const ORE_THRESHOLD: f32 = 0.82;
+const ORE_MIN_DEPTH: isize = 4;
+
+/// Salts so the height map, caves and ore don't share their noise
+const HEIGHT_SALT: u64 = 0x68_65_69_67_68_74;
+const CAVE_SALT: u64 = 0x63_61_76_65;
+const ORE_SALT: u64 = 0x6f_72_65;
+
+impl NoiseGenerator {
+    /// Generator for the seed, ore falls back to stone if the registry has no ore tile
+    /// and caves have no walls without a stone_wall tile
+    pub fn new(seed: u64, registry: &TileRegistry) -> NoiseGenerator {
+        let stone = registry.id_by_name("stone").expect("tile registry has no stone");
+        NoiseGenerator {
+            seed,
+            stone,
+            ore: registry.id_by_name("ore").unwrap_or(stone),
+            wall: registry.id_by_name("stone_wall").unwrap_or(TileId::EMPTY),
+        }
+    }
+
+    /// Row of the topmost ground cell of the column
+    pub fn surface_y(&self, x: isize) -> isize {
+        let x = x as f32 / HILL_WIDTH;
+        let height = (self.fractal_1d(x, HEIGHT_SALT) * 2.0 - 1.0) * HILL_HEIGHT;
+        (GROUND_LEVEL - height).round() as isize
+    }
+
+    fn cell(&self, x: isize, y: isize, surface_y: isize) -> TileId {
+        let depth = y - surface_y;
+        if depth < 0 {
+            return TileId::EMPTY;
+        }
+
+        let (fx, fy) = (x as f32, y as f32);
+        if depth >= CAVE_MIN_DEPTH
+            && self.fractal_2d(fx / CAVE_SCALE, fy / CAVE_SCALE, CAVE_SALT) > CAVE_THRESHOLD
+        {
+            return TileId::EMPTY;
+        }
+
+        if depth >= ORE_MIN_DEPTH && self.noise_2d(fx / ORE_SCALE, fy / ORE_SCALE, ORE_SALT) > ORE_THRESHOLD {
+            return self.ore;
+        }
+
+        self.stone
+    }
+
+    /// Value noise in `0..1`, three octaves
+    fn fractal_1d(&self, x: f32, salt: u64) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 0.5;
+        let mut frequency = 1.0;
+        for octave in 0..3 {
+            sum += self.noise_1d(x * frequency, salt + octave) * amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum / 0.875
+    }
+
+    /// Value noise in `0..1`, two octaves
+    fn fractal_2d(&self, x: f32, y: f32, salt: u64) -> f32 {
+        (self.noise_2d(x, y, salt) * 2.0 + self.noise_2d(x * 2.0, y * 2.0, salt + 1)) / 3.0
+    }
+
+    fn noise_1d(&self, x: f32, salt: u64) -> f32 {
+        let x0 = x.floor();
+        let t = smoothstep(x - x0);
+        let x0 = x0 as i64;
+        lerp(self.lattice(x0, 0, salt), self.lattice(x0 + 1, 0, salt), t)
+    }
+
+    fn noise_2d(&self, x: f32, y: f32, salt: u64) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (smoothstep(x - x0), smoothstep(y - y0));
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = lerp(self.lattice(x0, y0, salt), self.lattice(x0 + 1, y0, salt), tx);
+        let bottom = lerp(self.lattice(x0, y0 + 1, salt), self.lattice(x0 + 1, y0 + 1, salt), tx);
+        lerp(top, bottom, ty)
+    }
+
+    /// Random value in `0..1` at a lattice point
+    fn lattice(&self, x: i64, y: i64, salt: u64) -> f32 {
+        let hash = splitmix64(
+            self.seed
+                ^ splitmix64(salt)
+                ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f),
+        );
+        // 24 bits fit into the mantissa exactly
+        (hash >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+impl ChunkGenerator for NoiseGenerator {
+    fn generate(&self, chunk_x: isize, chunk_y: isize, layer: TileLayer, cells: &mut [TileId]) {
+        if layer == TileLayer::Foreground {
+            return;
+        }
+
+        for local_x in 0..CHUNK_SIZE {
+            let x = chunk_x * CHUNK_SIZE + local_x;
+            let surface_y = self.surface_y(x);
+
+            for local_y in 0..CHUNK_SIZE {
+                let y = chunk_y * CHUNK_SIZE + local_y;
+                cells[(local_y * CHUNK_SIZE + local_x) as usize] = match layer {
+                    TileLayer::Background if y >= surface_y => self.wall,
+                    TileLayer::Main => self.cell(x, y, surface_y),
+                    _ => TileId::EMPTY,
+                };
+            }
+        }
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tiles::{test_registry, TileId};
+    use crate::world::{TileLayer, Tilemap, CHUNK_SIZE};
+    use crate::worldgen::{ChunkGenerator, NoiseGenerator};
+    use ggez::graphics::Rect;
+
+    fn generate(generator: &NoiseGenerator, cx: isize, cy: isize) -> Vec<TileId> {
+        let mut cells = vec![TileId::EMPTY; (CHUNK_SIZE * CHUNK_SIZE) as usize];
+        generator.generate(cx, cy, TileLayer::Main, &mut cells);
+        cells
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let registry = test_registry();
+        let a = NoiseGenerator::new(42, &registry);
+        let b = NoiseGenerator::new(42, &registry);
+        let other_seed = NoiseGenerator::new(43, &registry);
+
+        let coords = [(0, 0), (-3, 1), (7, 2), (-1, -1)];
+        for &(cx, cy) in coords.iter() {
+            assert_eq!(generate(&a, cx, cy), generate(&b, cx, cy), "chunk ({}, {}) differs", cx, cy);
+        }
+        assert!(coords.iter().any(|&(cx, cy)| generate(&a, cx, cy) != generate(&other_seed, cx, cy)));
+
+        // ground starts at the surface and the surface is never undercut by caves
+        let stone = registry.id_by_name("stone").unwrap();
+        let mut tiles = Tilemap::with_generator(registry.clone(), Box::new(NoiseGenerator::new(42, &registry)));
+        for x in -40..40 {
+            let surface_y = a.surface_y(x);
+            tiles.request_area(Rect::new(x as f32, (surface_y - 1) as f32, 1.0, 2.0));
+            assert!(tiles.get_cell(x, surface_y - 1).is_empty());
+            assert_eq!(tiles.get_cell(x, surface_y), stone);
+        }
+    }
+}